@@ -1,109 +1,1635 @@
-use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::path::Path;
+use ipnet::IpNet;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::ip::IpVersion;
+
+/// Why a configuration file could not be used.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file: {}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file as TOML")]
+    Parse(#[from] toml::de::Error),
+    /// The file parsed but describes something auto-dns cannot do
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Returns early with a [`ConfigError::Invalid`], formatting the message like `format!`.
+macro_rules! invalid {
+    ($($arg:tt)*) => {
+        return Err(ConfigError::Invalid(format!($($arg)*)))
+    };
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub records: Vec<DnsRecord>,
+    #[serde(default)]
     pub aws: AwsConfig,
+    #[serde(default)]
+    pub ip_detection: IpDetectionConfig,
+    /// Where records get their address from unless they set their own `ip_source`
+    #[serde(default)]
+    pub ip_source: IpSourceConfig,
+    #[serde(default)]
+    pub propagation: PropagationConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub network_changes: NetworkChangesConfig,
+    /// Most records or zones checked and updated at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// How often records are checked in continuous mode, unless they set their own
+    #[serde(default = "default_interval")]
+    pub interval: HumanDuration,
+    /// Longest random delay added to each wait, so that many hosts started together
+    /// do not all call Route53 at the same moment
+    #[serde(default)]
+    pub interval_jitter: HumanDuration,
+    /// How long an update cycle in progress may take to finish after SIGTERM or SIGINT
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: HumanDuration,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DnsRecord {
+    pub name: String,
+    /// Looked up from the record name when omitted
+    #[serde(default)]
+    pub hosted_zone_id: Option<String>,
+    #[serde(default = "default_ttl")]
+    pub ttl: i64,
+    #[serde(default)]
+    pub record_type: RecordType,
+    #[serde(default)]
+    pub ip_source: Option<IpSourceConfig>,
+    /// Role for this record's account, replacing the one from `[aws]`
+    #[serde(flatten)]
+    pub assume_role: AssumeRoleConfig,
+    /// How often this record is checked, instead of the global `interval`
+    #[serde(default)]
+    pub interval: Option<HumanDuration>,
+}
+
+/// Which address records to keep up to date for a DNS name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordType {
+    #[default]
+    A,
+    Aaaa,
+    Both,
+}
+
+impl RecordType {
+    pub fn versions(self) -> &'static [IpVersion] {
+        match self {
+            RecordType::A => &[IpVersion::V4],
+            RecordType::Aaaa => &[IpVersion::V6],
+            RecordType::Both => &[IpVersion::V4, IpVersion::V6],
+        }
+    }
+}
+
+/// How to reach AWS. Anything left out comes from the standard AWS chain
+/// (environment variables, shared config files, SSO, web identity, ECS and IMDS).
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AwsConfig {
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Named profile from `~/.aws/config` and `~/.aws/credentials`
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+    /// Send Route53 requests here instead of AWS, e.g. to a local emulator
+    #[serde(default)]
+    pub endpoint_url: Option<String>,
+    #[serde(flatten)]
+    pub assume_role: AssumeRoleConfig,
+}
+
+/// An IAM role to assume with STS, e.g. in the account that owns the hosted zones.
+/// The role is assumed with the credentials from `[aws]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AssumeRoleConfig {
+    #[serde(default)]
+    pub role_arn: Option<String>,
+    /// Required by roles whose trust policy asks for one
+    #[serde(default)]
+    pub external_id: Option<String>,
+    /// Shown in CloudTrail; defaults to `auto-dns`
+    #[serde(default)]
+    pub session_name: Option<String>,
+}
+
+/// A role to assume, with defaults filled in. Records reached through the same
+/// role share one Route53 client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AssumeRole {
+    pub role_arn: String,
+    pub external_id: Option<String>,
+    pub session_name: String,
+}
+
+impl AssumeRoleConfig {
+    pub fn role(&self) -> Option<AssumeRole> {
+        Some(AssumeRole {
+            role_arn: self.role_arn.clone()?,
+            external_id: self.external_id.clone(),
+            session_name: self
+                .session_name
+                .clone()
+                .unwrap_or_else(|| "auto-dns".to_string()),
+        })
+    }
+
+    fn validate(&self, owner: &str) -> Result<(), ConfigError> {
+        let Some(role_arn) = &self.role_arn else {
+            if self.external_id.is_some() || self.session_name.is_some() {
+                invalid!("external_id and session_name need a role_arn ({})", owner);
+            }
+            return Ok(());
+        };
+        if !role_arn.starts_with("arn:") || !role_arn.contains(":role/") {
+            invalid!(
+                "Invalid role_arn {} ({}), expected arn:aws:iam::ACCOUNT:role/NAME",
+                role_arn,
+                owner
+            );
+        }
+        if self.external_id.as_deref() == Some("") {
+            invalid!("external_id cannot be empty ({})", owner);
+        }
+        if let Some(name) = &self.session_name {
+            // STS allows 2 to 64 characters from this set
+            let valid = (2..=64).contains(&name.len())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c));
+            if !valid {
+                invalid!(
+                    "Invalid session_name {:?} ({}), expected 2-64 letters, digits or _+=,.@-",
+                    name,
+                    owner
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AwsConfig {
+    /// The access key pair, if both halves are configured.
+    pub fn static_credentials(&self) -> Option<(&str, &str)> {
+        Some((
+            self.access_key_id.as_deref()?,
+            self.secret_access_key.as_deref()?,
+        ))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (field, value) in [
+            ("region", &self.region),
+            ("profile", &self.profile),
+            ("access key ID", &self.access_key_id),
+            ("secret access key", &self.secret_access_key),
+        ] {
+            if value.as_deref() == Some("") {
+                invalid!("AWS {} cannot be empty (omit it to use the default)", field);
+            }
+        }
+        if self.access_key_id.is_some() != self.secret_access_key.is_some() {
+            invalid!("AWS access_key_id and secret_access_key must be set together");
+        }
+        if self.profile.is_some() && self.access_key_id.is_some() {
+            invalid!("AWS profile cannot be combined with access_key_id and secret_access_key");
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            match reqwest::Url::parse(endpoint_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => invalid!("Invalid AWS endpoint_url: {}", endpoint_url),
+            }
+        }
+        self.assume_role.validate("[aws]")
+    }
+}
+
+/// How Route53 calls and IP detection are retried after throttling, timeouts and
+/// server errors, within one update cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Attempts in total, including the first; 1 disables retrying
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay in milliseconds before the first retry, doubled for each later one
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// Longest delay in milliseconds between two attempts
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Wait a random part of each delay, so that several instances do not retry in lockstep
+    #[serde(default = "default_true")]
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            base_delay_ms: default_retry_base_delay_ms(),
+            max_delay_ms: default_retry_max_delay_ms(),
+            jitter: true,
+        }
+    }
+}
+
+/// Checking every record as soon as an address or default route changes, instead of
+/// waiting for the next interval. Only available on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NetworkChangesConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// How long the network has to be quiet before the check starts, as one change
+    /// (e.g. a new DHCP lease) usually comes with several notifications
+    #[serde(default = "default_network_debounce")]
+    pub debounce: HumanDuration,
+}
+
+impl Default for NetworkChangesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            debounce: default_network_debounce(),
+        }
+    }
+}
+
+/// Where to remember what Route53 has, so that unchanged records are not looked up
+/// every cycle.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StateConfig {
+    /// State file; without one, every record is looked up every cycle
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// How long a remembered value is trusted before the record is looked up anyway
+    #[serde(default = "default_reconcile_interval")]
+    pub reconcile_interval: HumanDuration,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            reconcile_interval: default_reconcile_interval(),
+        }
+    }
+}
+
+/// Whether to wait for updates to reach all Route53 name servers before moving on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PropagationConfig {
+    #[serde(default)]
+    pub wait_for_sync: bool,
+    /// Timeout in seconds for a change to become `INSYNC`
+    #[serde(default = "default_sync_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for PropagationConfig {
+    fn default() -> Self {
+        Self {
+            wait_for_sync: false,
+            timeout: default_sync_timeout(),
+        }
+    }
+}
+
+/// A duration written like `90s`, `5m`, `1h30m` or `1d`, or as a number of seconds.
+/// Units are `ms`, `s`, `m`, `h` and `d`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumanDuration(pub Duration);
+
+const DURATION_UNITS: [(&str, u64); 5] = [
+    ("d", 86_400_000),
+    ("h", 3_600_000),
+    ("m", 60_000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+impl HumanDuration {
+    pub const fn from_secs(secs: u64) -> Self {
+        Self(Duration::from_secs(secs))
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid duration '{text}' (expected e.g. 90s, 5m or 1h30m)");
+        let mut rest = text.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut millis: u64 = 0;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+            let unit = rest
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let scale = DURATION_UNITS
+                .iter()
+                .find(|(name, _)| *name == rest[..unit].trim())
+                .map(|(_, scale)| *scale)
+                .ok_or_else(invalid)?;
+            millis = amount
+                .checked_mul(scale)
+                .and_then(|part| millis.checked_add(part))
+                .ok_or_else(invalid)?;
+            rest = &rest[unit..];
+        }
+        Ok(Self(Duration::from_millis(millis)))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut millis = self.0.as_millis() as u64;
+        if millis == 0 {
+            return write!(f, "0s");
+        }
+        for (name, scale) in DURATION_UNITS {
+            if millis >= scale {
+                write!(f, "{}{}", millis / scale, name)?;
+                millis %= scale;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match toml::Value::deserialize(deserializer)? {
+            toml::Value::Integer(secs) => u64::try_from(secs)
+                .map(Self::from_secs)
+                .map_err(|_| D::Error::custom(format!("duration cannot be negative: {secs}"))),
+            toml::Value::String(text) => text.parse().map_err(D::Error::custom),
+            other => Err(D::Error::custom(format!(
+                "duration must be a string like \"5m\" or a number of seconds, got {other}"
+            ))),
+        }
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where to learn the address published in a record.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IpSourceConfig {
+    /// Ask the HTTP services from `[ip_detection]`
+    #[default]
+    Services,
+    /// Read the address assigned to a local network interface
+    Interface(InterfaceSourceConfig),
+    /// Always publish the same address(es)
+    Static(StaticSourceConfig),
+    /// Read the address from a file, e.g. one written by a router hook
+    File(FileSourceConfig),
+    /// Run a program and read the address from its standard output
+    Command(CommandSourceConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct InterfaceSourceConfig {
+    pub interface: String,
+    #[serde(default)]
+    pub scope: AddressScope,
+    /// Only use addresses inside this network, e.g. `2001:db8:1234::/48`
+    #[serde(default)]
+    pub prefix: Option<IpNet>,
+    /// Skip RFC 1918, CGNAT and unique local (fc00::/7) addresses
+    #[serde(default = "default_true")]
+    pub exclude_private: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct StaticSourceConfig {
+    /// At most one address per family
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct FileSourceConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct CommandSourceConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Timeout in seconds
+    #[serde(default = "default_command_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressScope {
+    /// Skip loopback and link-local addresses
+    #[default]
+    Global,
+    Any,
+}
+
+/// Public IP detection services, tried in order until one answers.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IpDetectionConfig {
+    #[serde(default = "default_services")]
+    pub services: Vec<IpServiceConfig>,
+    #[serde(default = "default_services_v6")]
+    pub services_v6: Vec<IpServiceConfig>,
+    #[serde(default)]
+    pub mode: DetectionMode,
+    #[serde(default)]
+    pub consensus: ConsensusConfig,
+}
+
+impl Default for IpDetectionConfig {
+    fn default() -> Self {
+        Self {
+            services: default_services(),
+            services_v6: default_services_v6(),
+            mode: DetectionMode::default(),
+            consensus: ConsensusConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMode {
+    /// Use the first service that answers
+    #[default]
+    Failover,
+    /// Query several services at once and require a minimum number to agree
+    Consensus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConsensusConfig {
+    /// Number of services queried concurrently (taken from the front of the list)
+    #[serde(default = "default_consensus_queries")]
+    pub queries: usize,
+    /// Number of services that must report the same address
+    #[serde(default = "default_consensus_min_agree")]
+    pub min_agree: usize,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        Self {
+            queries: default_consensus_queries(),
+            min_agree: default_consensus_min_agree(),
+        }
+    }
+}
+
+/// One entry in the detection service list. Entries without a `type` are HTTP services.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IpServiceConfig {
+    Http(HttpServiceConfig),
+    Stun(StunServiceConfig),
+    Dns(DnsServiceConfig),
+    Upnp(UpnpServiceConfig),
+    NatPmp(GatewayServiceConfig),
+    Pcp(GatewayServiceConfig),
+}
+
+impl<'de> Deserialize<'de> for IpServiceConfig {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut table = toml::Table::deserialize(deserializer)?;
+        let kind = match table.remove("type") {
+            None => "http".to_string(),
+            Some(toml::Value::String(kind)) => kind,
+            Some(other) => {
+                return Err(D::Error::custom(format!(
+                    "service type must be a string, got {other}"
+                )))
+            }
+        };
+
+        let value = toml::Value::Table(table);
+        match kind.as_str() {
+            "http" => value.try_into().map(IpServiceConfig::Http),
+            "stun" => value.try_into().map(IpServiceConfig::Stun),
+            "dns" => value.try_into().map(IpServiceConfig::Dns),
+            "upnp" => value.try_into().map(IpServiceConfig::Upnp),
+            "natpmp" => value.try_into().map(IpServiceConfig::NatPmp),
+            "pcp" => value.try_into().map(IpServiceConfig::Pcp),
+            other => {
+                return Err(D::Error::unknown_variant(
+                    other,
+                    &["http", "stun", "dns", "upnp", "natpmp", "pcp"],
+                ))
+            }
+        }
+        .map_err(D::Error::custom)
+    }
+}
+
+impl IpServiceConfig {
+    /// Identifies the service in logs and errors
+    pub fn name(&self) -> String {
+        match self {
+            IpServiceConfig::Http(http) => http.url.clone(),
+            IpServiceConfig::Stun(stun) => format!("stun:{}", stun.server),
+            IpServiceConfig::Dns(dns) => format!("dns:{}@{}", dns.query, dns.resolver),
+            IpServiceConfig::Upnp(upnp) => {
+                format!("upnp:{}", upnp.location.as_deref().unwrap_or("discover"))
+            }
+            IpServiceConfig::NatPmp(gateway) => format!("natpmp:{}", gateway.gateway_name()),
+            IpServiceConfig::Pcp(gateway) => format!("pcp:{}", gateway.gateway_name()),
+        }
+    }
+
+    /// Non-public networks whose addresses are accepted from this service
+    pub fn allow(&self) -> &[IpNet] {
+        match self {
+            IpServiceConfig::Http(http) => &http.allow,
+            IpServiceConfig::Stun(stun) => &stun.allow,
+            IpServiceConfig::Dns(dns) => &dns.allow,
+            IpServiceConfig::Upnp(upnp) => &upnp.allow,
+            IpServiceConfig::NatPmp(gateway) | IpServiceConfig::Pcp(gateway) => &gateway.allow,
+        }
+    }
+
+    fn validate(&self, version: IpVersion) -> Result<(), ConfigError> {
+        match self {
+            IpServiceConfig::Http(http) => {
+                if http.url.is_empty() {
+                    invalid!("IP detection service URL cannot be empty");
+                }
+                if http.timeout == 0 {
+                    invalid!("Timeout must be positive for service: {}", http.url);
+                }
+            }
+            IpServiceConfig::Stun(stun) => {
+                if stun.server.is_empty() {
+                    invalid!("STUN server cannot be empty");
+                }
+                if stun.timeout == 0 {
+                    invalid!("Timeout must be positive for service: {}", self.name());
+                }
+            }
+            IpServiceConfig::Dns(dns) => {
+                if dns.resolver.is_empty() || dns.query.is_empty() {
+                    invalid!("DNS detection services need both a resolver and a query");
+                }
+                if dns.timeout == 0 {
+                    invalid!("Timeout must be positive for service: {}", self.name());
+                }
+                match (dns.record_type, version) {
+                    (Some(DnsQueryType::A), IpVersion::V6)
+                    | (Some(DnsQueryType::Aaaa), IpVersion::V4) => invalid!(
+                        "Service {} cannot detect {} addresses",
+                        self.name(),
+                        version
+                    ),
+                    _ => {}
+                }
+            }
+            IpServiceConfig::Upnp(upnp) => {
+                if upnp.location.as_deref() == Some("") {
+                    invalid!("UPnP location cannot be empty; omit it to use discovery");
+                }
+                if upnp.timeout == 0 {
+                    invalid!("Timeout must be positive for service: {}", self.name());
+                }
+                if version == IpVersion::V6 {
+                    invalid!("UPnP IGD gateways only report IPv4 addresses");
+                }
+            }
+            IpServiceConfig::NatPmp(gateway) | IpServiceConfig::Pcp(gateway) => {
+                if gateway.gateway.as_deref() == Some("") {
+                    invalid!("Gateway cannot be empty; omit it to use the default route");
+                }
+                if gateway.timeout == 0 {
+                    invalid!("Timeout must be positive for service: {}", self.name());
+                }
+                if version == IpVersion::V6 {
+                    if let IpServiceConfig::NatPmp(_) = self {
+                        invalid!("NAT-PMP gateways only report IPv4 addresses");
+                    }
+                    if gateway.gateway.is_none() {
+                        invalid!(
+                            "Service {} needs an explicit gateway to detect IPv6 addresses",
+                            self.name()
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HttpServiceConfig {
+    pub url: String,
+    /// Request timeout in seconds
+    #[serde(default = "default_service_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub format: ResponseFormat,
+    /// Non-public networks (e.g. `10.0.0.0/8`) this service may report; other
+    /// private, CGNAT, documentation and similar addresses are rejected
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+impl HttpServiceConfig {
+    fn plain(url: &str) -> IpServiceConfig {
+        IpServiceConfig::Http(Self {
+            url: url.to_string(),
+            timeout: default_service_timeout(),
+            headers: BTreeMap::new(),
+            format: ResponseFormat::Text,
+            allow: Vec::new(),
+        })
+    }
+}
+
+/// A STUN server (RFC 5389) that reports our mapped address in a binding response.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StunServiceConfig {
+    /// `host:port`; the port defaults to 3478
+    pub server: String,
+    /// Timeout in seconds
+    #[serde(default = "default_service_timeout")]
+    pub timeout: u64,
+    /// See [`HttpServiceConfig::allow`]
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+/// A DNS query whose answer is the caller's address, e.g. `myip.opendns.com`
+/// asked of an OpenDNS resolver, or `o-o.myaddr.l.google.com` TXT asked of ns1.google.com.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DnsServiceConfig {
+    /// `host:port`; the port defaults to 53
+    pub resolver: String,
+    pub query: String,
+    /// Defaults to A when detecting IPv4 and AAAA when detecting IPv6
+    #[serde(default)]
+    pub record_type: Option<DnsQueryType>,
+    /// Timeout in seconds
+    #[serde(default = "default_service_timeout")]
+    pub timeout: u64,
+    /// See [`HttpServiceConfig::allow`]
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+/// An UPnP Internet Gateway Device, asked for its external address with `GetExternalIPAddress`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UpnpServiceConfig {
+    /// URL of the gateway's device description; found with SSDP discovery when unset
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Timeout in seconds
+    #[serde(default = "default_service_timeout")]
+    pub timeout: u64,
+    /// See [`HttpServiceConfig::allow`]
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+/// A NAT-PMP (RFC 6886) or PCP (RFC 6887) gateway.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GatewayServiceConfig {
+    /// `host:port`; the port defaults to 5351 and the host to the IPv4 default gateway
+    #[serde(default)]
+    pub gateway: Option<String>,
+    /// Timeout in seconds
+    #[serde(default = "default_service_timeout")]
+    pub timeout: u64,
+    /// See [`HttpServiceConfig::allow`]
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+impl GatewayServiceConfig {
+    fn gateway_name(&self) -> &str {
+        self.gateway.as_deref().unwrap_or("default-gateway")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DnsQueryType {
+    A,
+    Aaaa,
+    Txt,
+}
+
+/// How to find the IP address in a service's response body.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// The whole body is the address
+    #[default]
+    Text,
+    /// A JSON document with the address at a dot-separated field path, e.g. `data.ip`
+    Json { field: String },
+    /// The first match of a regex; capture group 1 is used if the pattern has one
+    Regex { pattern: String },
+}
+
+fn default_ttl() -> i64 {
+    300 // 5 minutes
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_command_timeout() -> u64 {
+    30
+}
+
+fn default_service_timeout() -> u64 {
+    10
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    1000
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    20_000
+}
+
+fn default_reconcile_interval() -> HumanDuration {
+    HumanDuration::from_secs(86_400)
+}
+
+fn default_network_debounce() -> HumanDuration {
+    HumanDuration::from_secs(2)
+}
+
+fn default_interval() -> HumanDuration {
+    HumanDuration::from_secs(300)
+}
+
+fn default_shutdown_timeout() -> HumanDuration {
+    HumanDuration::from_secs(5)
+}
+
+fn default_concurrency() -> usize {
+    8
+}
+
+fn default_sync_timeout() -> u64 {
+    300
+}
+
+fn default_consensus_queries() -> usize {
+    3
+}
+
+fn default_consensus_min_agree() -> usize {
+    2
+}
+
+fn default_services() -> Vec<IpServiceConfig> {
+    [
+        "https://api.ipify.org",
+        "https://icanhazip.com",
+        "https://ifconfig.me/ip",
+        "https://checkip.amazonaws.com",
+        "https://ipecho.net/plain",
+    ]
+    .into_iter()
+    .map(HttpServiceConfig::plain)
+    .collect()
+}
+
+fn default_services_v6() -> Vec<IpServiceConfig> {
+    // These endpoints only listen on IPv6, so they always report the v6 address
+    [
+        "https://api6.ipify.org",
+        "https://ipv6.icanhazip.com",
+        "https://v6.ident.me",
+    ]
+    .into_iter()
+    .map(HttpServiceConfig::plain)
+    .collect()
+}
+
+impl Config {
+    /// How often `record` is checked: its own interval, else the global one.
+    pub fn interval_for(&self, record: &DnsRecord) -> Duration {
+        record.interval.unwrap_or(self.interval).0
+    }
+
+    /// What changed from this config to `new`, one line per setting, e.g.
+    /// `interval: 5m -> 1m` or `record home.example.com: ttl: 300 -> 60`.
+    /// Records are matched up by name; credentials and HTTP header values are
+    /// only reported as changed.
+    pub fn diff(&self, new: &Config) -> Vec<String> {
+        let records = |config: &Config| -> BTreeMap<String, BTreeMap<String, Setting>> {
+            config
+                .records
+                .iter()
+                .map(|record| (record.name.clone(), settings(record)))
+                .collect()
+        };
+        let (old_records, new_records) = (records(self), records(new));
+
+        let mut changes = Vec::new();
+        for (name, old) in &old_records {
+            match new_records.get(name) {
+                Some(new) => changes.extend(
+                    diff_settings(old, new)
+                        .into_iter()
+                        .map(|change| format!("record {name}: {change}")),
+                ),
+                None => changes.push(format!("record {name}: removed")),
+            }
+        }
+        for name in new_records.keys() {
+            if !old_records.contains_key(name) {
+                changes.push(format!("record {name}: added"));
+            }
+        }
+        // Everything but the records, which are a single array here
+        let others = |config: &Config| {
+            let mut settings = settings(config);
+            settings.remove("records");
+            settings
+        };
+        changes.extend(diff_settings(&others(self), &others(new)));
+        changes
+    }
+
+    /// The role a record's changes are made through: its own, else the one from `[aws]`.
+    pub fn role_for(&self, record: &DnsRecord) -> Option<AssumeRole> {
+        record
+            .assume_role
+            .role()
+            .or_else(|| self.aws.assume_role.role())
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path_ref = path.as_ref();
+        let content = tokio::fs::read_to_string(path_ref)
+            .await
+            .map_err(|source| ConfigError::Read {
+                path: path_ref.to_path_buf(),
+                source,
+            })?;
+
+        let config: Config = toml::from_str(&content)?;
+
+        // Validate configuration
+        if config.records.is_empty() {
+            invalid!("No DNS records configured");
+        }
+
+        config.aws.validate()?;
+
+        for record in &config.records {
+            if record.name.is_empty() {
+                invalid!("DNS record name cannot be empty");
+            }
+            if record.hosted_zone_id.as_deref() == Some("") {
+                invalid!(
+                    "Hosted zone ID cannot be empty for record: {} (omit it to look the zone up by name)",
+                    record.name
+                );
+            }
+            if record.ttl <= 0 {
+                invalid!("TTL must be positive for record: {}", record.name);
+            }
+            if let Some(source) = &record.ip_source {
+                source.validate()?;
+            }
+            record
+                .assume_role
+                .validate(&format!("record {}", record.name))?;
+            if record.interval.is_some_and(|interval| interval.0.is_zero()) {
+                invalid!("Interval must be positive for record: {}", record.name);
+            }
+        }
+        config.ip_source.validate()?;
+
+        if config.propagation.timeout == 0 {
+            invalid!("Propagation timeout must be positive");
+        }
+        if config.retry.max_attempts == 0 {
+            invalid!("Retry max_attempts must be at least 1");
+        }
+        if config.retry.base_delay_ms > config.retry.max_delay_ms {
+            invalid!("Retry base_delay_ms cannot be larger than max_delay_ms");
+        }
+        if config
+            .state
+            .path
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            invalid!("State file path cannot be empty");
+        }
+        if config.concurrency == 0 {
+            invalid!("Concurrency must be at least 1");
+        }
+        if config.interval.0.is_zero() {
+            invalid!("Interval must be positive");
+        }
+
+        // Validate IP detection services
+        for (version, services) in [
+            (IpVersion::V4, &config.ip_detection.services),
+            (IpVersion::V6, &config.ip_detection.services_v6),
+        ] {
+            let needed = config
+                .records
+                .iter()
+                .any(|r| r.record_type.versions().contains(&version));
+            if needed && services.is_empty() {
+                invalid!("No {} detection services configured", version);
+            }
+
+            for service in services {
+                service.validate(version)?;
+            }
+        }
+
+        if config.ip_detection.mode == DetectionMode::Consensus {
+            let consensus = &config.ip_detection.consensus;
+            if consensus.min_agree == 0 {
+                invalid!("Consensus min_agree must be positive");
+            }
+            if consensus.min_agree > consensus.queries {
+                invalid!(
+                    "Consensus min_agree ({}) cannot exceed queries ({})",
+                    consensus.min_agree,
+                    consensus.queries
+                );
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Settings that are never logged: changes to them are only reported as such.
+const SECRET_SETTINGS: [&str; 3] = ["access_key_id", "secret_access_key", "external_id"];
+
+/// A setting's value, and how it may be shown in the log.
+#[derive(Debug, PartialEq)]
+struct Setting {
+    value: String,
+    shown: String,
+}
+
+/// Flattens `value` into its settings by dotted path, e.g. `retry.max_attempts`,
+/// leaving out the unset ones. Arrays are kept whole, as JSON. Secrets, and the
+/// values of HTTP headers (which carry API tokens), are shown as `<redacted>`.
+fn settings(value: &impl Serialize) -> BTreeMap<String, Setting> {
+    fn flatten(path: String, value: serde_json::Value, settings: &mut BTreeMap<String, String>) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (key, value) in fields {
+                    let path = match path.as_str() {
+                        "" => key,
+                        _ => format!("{path}.{key}"),
+                    };
+                    flatten(path, value, settings);
+                }
+            }
+            serde_json::Value::Null => {}
+            serde_json::Value::String(value) => {
+                settings.insert(path, value);
+            }
+            value => {
+                settings.insert(path, value.to_string());
+            }
+        }
+    }
+
+    fn redact(value: &mut serde_json::Value) {
+        let redacted = || serde_json::Value::String("<redacted>".to_string());
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (key, value) in fields.iter_mut() {
+                    if value.is_null() {
+                        continue;
+                    }
+                    if SECRET_SETTINGS.contains(&key.as_str()) {
+                        *value = redacted();
+                    } else if key == "headers" {
+                        if let serde_json::Value::Object(headers) = value {
+                            headers.values_mut().for_each(|value| *value = redacted());
+                        }
+                    } else {
+                        redact(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+
+    let Ok(value) = serde_json::to_value(value) else {
+        return BTreeMap::new();
+    };
+    let mut shown_value = value.clone();
+    redact(&mut shown_value);
+    let (mut values, mut shown) = (BTreeMap::new(), BTreeMap::new());
+    flatten(String::new(), value, &mut values);
+    flatten(String::new(), shown_value, &mut shown);
+    values
+        .into_iter()
+        .map(|(path, value)| {
+            let shown = shown.remove(&path).unwrap_or_default();
+            (path, Setting { value, shown })
+        })
+        .collect()
+}
+
+/// One line for each setting that differs between `old` and `new`. Where the
+/// difference is only in redacted parts, the setting is just reported as changed.
+fn diff_settings(old: &BTreeMap<String, Setting>, new: &BTreeMap<String, Setting>) -> Vec<String> {
+    let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (old, new) = (old.get(path), new.get(path));
+            if old == new {
+                return None;
+            }
+            let (old, new) = (
+                old.map_or("unset", |s| s.shown.as_str()),
+                new.map_or("unset", |s| s.shown.as_str()),
+            );
+            if old == new || old.contains("<redacted>") || new.contains("<redacted>") {
+                Some(format!("{path}: changed"))
+            } else {
+                Some(format!("{path}: {old} -> {new}"))
+            }
+        })
+        .collect()
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct DnsRecord {
-    pub name: String,
-    pub hosted_zone_id: String,
-    #[serde(default = "default_ttl")]
-    pub ttl: i64,
-}
+impl fmt::Display for IpSourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpSourceConfig::Services => write!(f, "detection services"),
+            IpSourceConfig::Interface(interface) => {
+                write!(f, "interface {}", interface.interface)
+            }
+            IpSourceConfig::Static(_) => write!(f, "static addresses"),
+            IpSourceConfig::File(file) => write!(f, "file {}", file.path.display()),
+            IpSourceConfig::Command(command) => write!(f, "command {}", command.command),
+        }
+    }
+}
+
+impl IpSourceConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            IpSourceConfig::Services => {}
+            IpSourceConfig::Interface(interface) => {
+                if interface.interface.is_empty() {
+                    invalid!("Interface name cannot be empty for interface IP source");
+                }
+            }
+            IpSourceConfig::Static(source) => {
+                if source.addresses.is_empty() {
+                    invalid!("Static IP source needs at least one address");
+                }
+                if source.addresses.iter().filter(|ip| ip.is_ipv4()).count() > 1
+                    || source.addresses.iter().filter(|ip| ip.is_ipv6()).count() > 1
+                {
+                    invalid!("Static IP source can have at most one address per family");
+                }
+            }
+            IpSourceConfig::File(file) => {
+                if file.path.as_os_str().is_empty() {
+                    invalid!("Path cannot be empty for file IP source");
+                }
+            }
+            IpSourceConfig::Command(command) => {
+                if command.command.is_empty() {
+                    invalid!("Command cannot be empty for command IP source");
+                }
+                if command.timeout == 0 {
+                    invalid!("Timeout must be positive for command: {}", command.command);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            records: vec![DnsRecord {
+                name: "example.com".to_string(),
+                hosted_zone_id: Some("Z1234567890ABC".to_string()),
+                ttl: 300,
+                record_type: RecordType::A,
+                ip_source: None,
+                assume_role: AssumeRoleConfig::default(),
+                interval: None,
+            }],
+            aws: AwsConfig::default(),
+            ip_detection: IpDetectionConfig::default(),
+            ip_source: IpSourceConfig::default(),
+            propagation: PropagationConfig::default(),
+            retry: RetryConfig::default(),
+            state: StateConfig::default(),
+            network_changes: NetworkChangesConfig::default(),
+            concurrency: default_concurrency(),
+            interval: default_interval(),
+            interval_jitter: HumanDuration::default(),
+            shutdown_timeout: default_shutdown_timeout(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_load_valid_config() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+ttl = 600
+
+[[records]]
+name = "api.example.com"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(config.records.len(), 2);
+        assert_eq!(config.records[0].name, "test.example.com");
+        assert_eq!(
+            config.records[0].hosted_zone_id.as_deref(),
+            Some("Z1234567890ABC")
+        );
+        assert_eq!(config.records[1].hosted_zone_id, None); // looked up by name
+        assert_eq!(config.records[0].ttl, 600);
+        assert_eq!(config.records[1].ttl, 300); // default TTL
+        assert_eq!(config.records[0].record_type, RecordType::A); // default type
+        assert_eq!(config.ip_detection.services.len(), 5); // default services
+        assert_eq!(
+            config.ip_detection.services[0].name(),
+            "https://api.ipify.org"
+        );
+        assert_eq!(config.ip_detection.services_v6.len(), 3);
+        assert_eq!(config.ip_detection.mode, DetectionMode::Failover);
+        assert_eq!(config.ip_source, IpSourceConfig::Services);
+        assert_eq!(config.records[0].ip_source, None);
+    }
+
+    #[tokio::test]
+    async fn test_load_interface_sources() {
+        let config_content = r#"
+[ip_source]
+type = "interface"
+interface = "eth0"
+
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[[records]]
+name = "vpn.example.com"
+hosted_zone_id = "Z1234567890ABC"
+record_type = "AAAA"
+ip_source = { type = "interface", interface = "wg0", scope = "any", prefix = "2001:db8:1234::/48", exclude_private = false }
+
+[[records]]
+name = "web.example.com"
+hosted_zone_id = "Z1234567890ABC"
+ip_source = { type = "services" }
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(
+            config.ip_source,
+            IpSourceConfig::Interface(InterfaceSourceConfig {
+                interface: "eth0".to_string(),
+                scope: AddressScope::Global,
+                prefix: None,
+                exclude_private: true,
+            })
+        );
+        assert_eq!(config.records[0].ip_source, None);
+        assert_eq!(
+            config.records[1].ip_source,
+            Some(IpSourceConfig::Interface(InterfaceSourceConfig {
+                interface: "wg0".to_string(),
+                scope: AddressScope::Any,
+                prefix: Some("2001:db8:1234::/48".parse().unwrap()),
+                exclude_private: false,
+            }))
+        );
+        assert_eq!(config.records[2].ip_source, Some(IpSourceConfig::Services));
+    }
+
+    #[tokio::test]
+    async fn test_load_consensus_mode() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+
+[ip_detection]
+mode = "consensus"
+consensus = { queries = 5, min_agree = 3 }
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(config.ip_detection.mode, DetectionMode::Consensus);
+        assert_eq!(
+            config.ip_detection.consensus,
+            ConsensusConfig {
+                queries: 5,
+                min_agree: 3
+            }
+        );
+
+        let invalid = config_content.replace("min_agree = 3", "min_agree = 6");
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(invalid.as_bytes()).unwrap();
+
+        let result = Config::load(temp_file.path()).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("min_agree (6) cannot exceed queries (5)"));
+    }
+
+    #[tokio::test]
+    async fn test_load_ip_detection_services() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+
+[[ip_detection.services]]
+url = "https://whatismyip.corp.example/ip"
+timeout = 3
+headers = { Authorization = "Bearer token" }
+
+[[ip_detection.services]]
+url = "https://api.example.net/v1/ip"
+format = { type = "json", field = "data.ip" }
+
+[[ip_detection.services]]
+url = "https://example.org/status"
+format = { type = "regex", pattern = 'Current IP Address: ([0-9.]+)' }
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        let services: Vec<_> = config
+            .ip_detection
+            .services
+            .iter()
+            .map(|service| match service {
+                IpServiceConfig::Http(http) => http,
+                other => panic!("Expected an HTTP service, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(services.len(), 3);
+        assert_eq!(services[0].timeout, 3);
+        assert_eq!(services[0].headers["Authorization"], "Bearer token");
+        assert_eq!(services[0].format, ResponseFormat::Text);
+        assert_eq!(services[1].timeout, 10); // default timeout
+        assert_eq!(
+            services[1].format,
+            ResponseFormat::Json {
+                field: "data.ip".to_string()
+            }
+        );
+        assert_eq!(
+            services[2].format,
+            ResponseFormat::Regex {
+                pattern: "Current IP Address: ([0-9.]+)".to_string()
+            }
+        );
+        // IPv6 services keep their defaults when only IPv4 ones are overridden
+        assert_eq!(config.ip_detection.services_v6.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_load_stun_services() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct AwsConfig {
-    pub access_key_id: String,
-    pub secret_access_key: String,
-}
+[[ip_detection.services]]
+type = "stun"
+server = "stun.l.google.com:19302"
 
-impl AwsConfig {
-    pub fn region(&self) -> String {
-        "us-east-1".to_string()
+[[ip_detection.services]]
+type = "http"
+url = "https://api.ipify.org"
+
+[[ip_detection.services]]
+type = "stun"
+server = "stun.example.net"
+timeout = 2
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        let names: Vec<_> = config
+            .ip_detection
+            .services
+            .iter()
+            .map(IpServiceConfig::name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "stun:stun.l.google.com:19302",
+                "https://api.ipify.org",
+                "stun:stun.example.net"
+            ]
+        );
+        match &config.ip_detection.services[2] {
+            IpServiceConfig::Stun(stun) => assert_eq!(stun.timeout, 2),
+            other => panic!("Expected a STUN service, got {other:?}"),
+        }
+
+        let unknown = config_content.replace(r#"type = "http""#, r#"type = "carrier-pigeon""#);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(unknown.as_bytes()).unwrap();
+
+        let result = Config::load(temp_file.path()).await;
+        let err = anyhow::Error::new(result.unwrap_err());
+        assert!(format!("{err:#}").contains("unknown variant `carrier-pigeon`"));
     }
-}
 
-fn default_ttl() -> i64 {
-    300 // 5 minutes
-}
+    #[tokio::test]
+    async fn test_load_dns_services() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+record_type = "BOTH"
 
-impl Config {
-    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let path_ref = path.as_ref();
-        let content = tokio::fs::read_to_string(path_ref)
-            .await
-            .with_context(|| format!("Failed to read config file: {}", path_ref.display()))?;
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
 
-        let config: Config =
-            toml::from_str(&content).with_context(|| "Failed to parse config file as TOML")?;
+[[ip_detection.services]]
+type = "dns"
+resolver = "208.67.222.222"
+query = "myip.opendns.com"
 
-        // Validate configuration
-        if config.records.is_empty() {
-            anyhow::bail!("No DNS records configured");
-        }
+[[ip_detection.services]]
+type = "dns"
+resolver = "ns1.google.com"
+query = "o-o.myaddr.l.google.com"
+record_type = "TXT"
 
-        // Validate AWS configuration
-        if config.aws.access_key_id.is_empty() {
-            anyhow::bail!("AWS access key ID cannot be empty");
-        }
-        if config.aws.secret_access_key.is_empty() {
-            anyhow::bail!("AWS secret access key cannot be empty");
-        }
+[[ip_detection.services_v6]]
+type = "dns"
+resolver = "[2620:119:35::35]:53"
+query = "myip.opendns.com"
+record_type = "AAAA"
+"#;
 
-        for record in &config.records {
-            if record.name.is_empty() {
-                anyhow::bail!("DNS record name cannot be empty");
-            }
-            if record.hosted_zone_id.is_empty() {
-                anyhow::bail!("Hosted zone ID cannot be empty for record: {}", record.name);
-            }
-            if record.ttl <= 0 {
-                anyhow::bail!("TTL must be positive for record: {}", record.name);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        match &config.ip_detection.services[1] {
+            IpServiceConfig::Dns(dns) => {
+                assert_eq!(dns.resolver, "ns1.google.com");
+                assert_eq!(dns.query, "o-o.myaddr.l.google.com");
+                assert_eq!(dns.record_type, Some(DnsQueryType::Txt));
+                assert_eq!(dns.timeout, 10);
             }
+            other => panic!("Expected a DNS service, got {other:?}"),
         }
+        assert_eq!(
+            config.ip_detection.services_v6[0].name(),
+            "dns:myip.opendns.com@[2620:119:35::35]:53"
+        );
 
-        Ok(config)
+        // An A query can never answer with an IPv6 address
+        let mismatched = config_content.replace(r#"record_type = "AAAA""#, r#"record_type = "A""#);
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(mismatched.as_bytes()).unwrap();
+
+        let result = Config::load(temp_file.path()).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("cannot detect IPv6 addresses"));
     }
-}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            records: vec![DnsRecord {
-                name: "example.com".to_string(),
-                hosted_zone_id: "Z1234567890ABC".to_string(),
-                ttl: 300,
-            }],
-            aws: AwsConfig {
-                access_key_id: "AKIA...".to_string(),
-                secret_access_key: "...".to_string(),
-            },
+    #[tokio::test]
+    async fn test_load_gateway_services() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+
+[[ip_detection.services]]
+type = "upnp"
+
+[[ip_detection.services]]
+type = "upnp"
+location = "http://192.168.1.1:5000/rootDesc.xml"
+timeout = 3
+
+[[ip_detection.services]]
+type = "natpmp"
+
+[[ip_detection.services]]
+type = "pcp"
+gateway = "192.168.1.1"
+allow = ["100.64.0.0/10"]
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        let names: Vec<_> = config
+            .ip_detection
+            .services
+            .iter()
+            .map(IpServiceConfig::name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "upnp:discover",
+                "upnp:http://192.168.1.1:5000/rootDesc.xml",
+                "natpmp:default-gateway",
+                "pcp:192.168.1.1"
+            ]
+        );
+        match &config.ip_detection.services[1] {
+            IpServiceConfig::Upnp(upnp) => assert_eq!(upnp.timeout, 3),
+            other => panic!("Expected a UPnP service, got {other:?}"),
         }
-    }
-}
+        assert!(config.ip_detection.services[0].allow().is_empty());
+        assert_eq!(
+            config.ip_detection.services[3].allow(),
+            ["100.64.0.0/10".parse::<IpNet>().unwrap()]
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+        // NAT-PMP has no way to report an IPv6 address
+        let v6 = format!(
+            "{}\n[[ip_detection.services_v6]]\ntype = \"natpmp\"\n",
+            config_content.replace("[[records]]", "[[records]]\nrecord_type = \"BOTH\"")
+        );
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(v6.as_bytes()).unwrap();
+
+        let result = Config::load(temp_file.path()).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("NAT-PMP gateways only report IPv4 addresses"));
+    }
 
     #[tokio::test]
-    async fn test_load_valid_config() {
+    async fn test_load_empty_services_for_needed_family() {
         let config_content = r#"
 [[records]]
 name = "test.example.com"
 hosted_zone_id = "Z1234567890ABC"
-ttl = 600
 
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+
+[ip_detection]
+services = []
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let result = Config::load(temp_file.path()).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("No IPv4 detection services configured"));
+    }
+
+    #[tokio::test]
+    async fn test_load_record_types() {
+        let config_content = r#"
 [[records]]
-name = "api.example.com"
+name = "v6.example.com"
+hosted_zone_id = "Z1234567890ABC"
+record_type = "AAAA"
+
+[[records]]
+name = "dual.example.com"
 hosted_zone_id = "Z1234567890ABC"
+record_type = "BOTH"
 
 [aws]
 access_key_id = "AKIATEST"
@@ -114,10 +1640,13 @@ secret_access_key = "test-secret"
         temp_file.write_all(config_content.as_bytes()).unwrap();
 
         let config = Config::load(temp_file.path()).await.unwrap();
-        assert_eq!(config.records.len(), 2);
-        assert_eq!(config.records[0].name, "test.example.com");
-        assert_eq!(config.records[0].ttl, 600);
-        assert_eq!(config.records[1].ttl, 300); // default TTL
+        assert_eq!(config.records[0].record_type, RecordType::Aaaa);
+        assert_eq!(config.records[0].record_type.versions(), &[IpVersion::V6]);
+        assert_eq!(config.records[1].record_type, RecordType::Both);
+        assert_eq!(
+            config.records[1].record_type.versions(),
+            &[IpVersion::V4, IpVersion::V6]
+        );
     }
 
     #[tokio::test]
@@ -160,6 +1689,396 @@ secret_access_key = "test-secret"
         let config = Config::load(temp_file.path()).await.unwrap();
         assert_eq!(config.records.len(), 1);
         assert_eq!(config.records[0].name, "test.example.com");
-        assert_eq!(config.aws.access_key_id, "AKIATEST");
+        assert_eq!(config.aws.region, None);
+        assert_eq!(
+            config.aws.static_credentials(),
+            Some(("AKIATEST", "test-secret"))
+        );
+        assert_eq!(config.propagation, PropagationConfig::default());
+        assert_eq!(config.retry, RetryConfig::default());
+        assert_eq!(config.concurrency, 8);
+        assert_eq!(config.state, StateConfig::default());
+        assert_eq!(config.interval, HumanDuration::from_secs(300));
+        assert_eq!(config.shutdown_timeout, HumanDuration::from_secs(5));
+        assert!(config.network_changes.enabled);
+        assert_eq!(config.network_changes.debounce, HumanDuration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_load_aws_settings() {
+        let records = r#"
+[[records]]
+name = "test.example.com"
+"#;
+        let load = |aws: &str| {
+            let mut temp_file = NamedTempFile::new().unwrap();
+            temp_file
+                .write_all(format!("{records}{aws}").as_bytes())
+                .unwrap();
+            async move { Config::load(temp_file.path()).await }
+        };
+
+        // Everything comes from the default chain
+        let config = load("").await.unwrap();
+        assert_eq!(config.aws.region, None);
+        assert_eq!(config.aws.profile, None);
+        assert_eq!(config.aws.static_credentials(), None);
+
+        let config = load("[aws]\nregion = \"eu-west-1\"\nprofile = \"dns\"\n")
+            .await
+            .unwrap();
+        assert_eq!(config.aws.region.as_deref(), Some("eu-west-1"));
+        assert_eq!(config.aws.profile.as_deref(), Some("dns"));
+
+        let config = load("[aws]\nendpoint_url = \"http://localhost:4566\"\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            config.aws.endpoint_url.as_deref(),
+            Some("http://localhost:4566")
+        );
+
+        for (aws, expected) in [
+            (
+                "[aws]\naccess_key_id = \"AKIATEST\"\n",
+                "must be set together",
+            ),
+            (
+                "[aws]\nprofile = \"dns\"\naccess_key_id = \"AKIATEST\"\nsecret_access_key = \"s\"\n",
+                "profile cannot be combined",
+            ),
+            ("[aws]\nregion = \"\"\n", "AWS region cannot be empty"),
+            (
+                "[aws]\nendpoint_url = \"localhost:4566\"\n",
+                "Invalid AWS endpoint_url",
+            ),
+        ] {
+            let err = load(aws).await.unwrap_err();
+            assert!(err.to_string().contains(expected), "{aws}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_assume_role() {
+        let config_content = r#"
+[[records]]
+name = "home.example.com"
+
+[[records]]
+name = "office.example.org"
+role_arn = "arn:aws:iam::222222222222:role/dns"
+
+[aws]
+role_arn = "arn:aws:iam::111111111111:role/dns"
+external_id = "shared-secret"
+session_name = "home-router"
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(
+            config.role_for(&config.records[0]),
+            Some(AssumeRole {
+                role_arn: "arn:aws:iam::111111111111:role/dns".to_string(),
+                external_id: Some("shared-secret".to_string()),
+                session_name: "home-router".to_string(),
+            })
+        );
+        // A record's own role replaces the global one entirely
+        assert_eq!(
+            config.role_for(&config.records[1]),
+            Some(AssumeRole {
+                role_arn: "arn:aws:iam::222222222222:role/dns".to_string(),
+                external_id: None,
+                session_name: "auto-dns".to_string(),
+            })
+        );
+
+        for (aws, expected) in [
+            ("external_id = \"x\"", "need a role_arn ([aws])"),
+            ("role_arn = \"dns\"", "Invalid role_arn dns"),
+            (
+                "role_arn = \"arn:aws:iam::111111111111:role/dns\"\nsession_name = \"a b\"",
+                "Invalid session_name",
+            ),
+        ] {
+            let mut temp_file = NamedTempFile::new().unwrap();
+            write!(
+                temp_file,
+                "[[records]]\nname = \"a.example.com\"\n[aws]\n{aws}\n"
+            )
+            .unwrap();
+            let err = Config::load(temp_file.path()).await.unwrap_err();
+            assert!(err.to_string().contains(expected), "{aws}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_propagation() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+hosted_zone_id = "Z1234567890ABC"
+
+[aws]
+access_key_id = "AKIATEST"
+secret_access_key = "test-secret"
+
+[propagation]
+wait_for_sync = true
+timeout = 120
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert!(config.propagation.wait_for_sync);
+        assert_eq!(config.propagation.timeout, 120);
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file
+            .write_all(config_content.replace("120", "0").as_bytes())
+            .unwrap();
+        let result = Config::load(temp_file.path()).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Propagation timeout must be positive"));
+    }
+
+    #[tokio::test]
+    async fn test_load_retry() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+
+[retry]
+max_attempts = 5
+base_delay_ms = 250
+jitter = false
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(
+            config.retry,
+            RetryConfig {
+                max_attempts: 5,
+                base_delay_ms: 250,
+                max_delay_ms: 20_000,
+                jitter: false,
+            }
+        );
+
+        for (retry, expected) in [
+            ("max_attempts = 0", "at least 1"),
+            (
+                "base_delay_ms = 5000\nmax_delay_ms = 1000",
+                "cannot be larger than max_delay_ms",
+            ),
+        ] {
+            let mut temp_file = NamedTempFile::new().unwrap();
+            write!(
+                temp_file,
+                "[[records]]\nname = \"a.example.com\"\n[retry]\n{retry}\n"
+            )
+            .unwrap();
+            let err = Config::load(temp_file.path()).await.unwrap_err();
+            assert!(err.to_string().contains(expected), "{retry}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_human_duration() {
+        for (text, secs) in [
+            ("90s", 90),
+            ("5m", 300),
+            ("1h30m", 5400),
+            ("1h 30m", 5400),
+            ("2d", 172_800),
+            ("0s", 0),
+        ] {
+            let parsed: HumanDuration = text.parse().unwrap();
+            assert_eq!(parsed, HumanDuration::from_secs(secs), "{text}");
+        }
+        assert_eq!(
+            "1500ms".parse::<HumanDuration>().unwrap().0,
+            Duration::from_millis(1500)
+        );
+        for invalid in ["", "5", "m", "5x", "-5m", "99999999999999999d"] {
+            assert!(invalid.parse::<HumanDuration>().is_err(), "{invalid}");
+        }
+
+        assert_eq!(HumanDuration::from_secs(5400).to_string(), "1h30m");
+        assert_eq!(HumanDuration::from_secs(300).to_string(), "5m");
+        assert_eq!(
+            HumanDuration(Duration::from_millis(1500)).to_string(),
+            "1s500ms"
+        );
+        assert_eq!(HumanDuration::default().to_string(), "0s");
+    }
+
+    #[tokio::test]
+    async fn test_load_intervals() {
+        let config_content = r#"
+interval = "1m"
+interval_jitter = 10
+
+[[records]]
+name = "failover.example.com"
+interval = "15s"
+
+[[records]]
+name = "static.example.com"
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(config.interval_jitter, HumanDuration::from_secs(10));
+        assert_eq!(
+            config.interval_for(&config.records[0]),
+            Duration::from_secs(15)
+        );
+        assert_eq!(
+            config.interval_for(&config.records[1]),
+            Duration::from_secs(60)
+        );
+
+        for (setting, expected) in [
+            ("interval = \"soon\"", "invalid duration 'soon'"),
+            ("interval = \"0s\"", "Interval must be positive"),
+            ("interval = -5", "cannot be negative"),
+        ] {
+            let mut temp_file = NamedTempFile::new().unwrap();
+            write!(
+                temp_file,
+                "{setting}\n[[records]]\nname = \"a.example.com\"\n"
+            )
+            .unwrap();
+            let err = Config::load(temp_file.path()).await.unwrap_err();
+            let err = format!("{err}: {:?}", std::error::Error::source(&err));
+            assert!(err.contains(expected), "{setting}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_state() {
+        let config_content = r#"
+[[records]]
+name = "test.example.com"
+
+[state]
+path = "/var/lib/auto-dns/state.json"
+reconcile_interval = "12h"
+"#;
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        temp_file.write_all(config_content.as_bytes()).unwrap();
+
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(
+            config.state,
+            StateConfig {
+                path: Some(PathBuf::from("/var/lib/auto-dns/state.json")),
+                reconcile_interval: HumanDuration::from_secs(43_200),
+            }
+        );
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(
+            temp_file,
+            "[[records]]\nname = \"a.example.com\"\n[state]\npath = \"\"\n"
+        )
+        .unwrap();
+        let err = Config::load(temp_file.path()).await.unwrap_err();
+        assert!(err.to_string().contains("State file path cannot be empty"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Config::default();
+        assert!(old.diff(&Config::default()).is_empty());
+
+        let mut new = Config::default();
+        new.records[0].ttl = 60;
+        new.records.push(DnsRecord {
+            name: "new.example.com".to_string(),
+            ..Config::default().records.remove(0)
+        });
+        new.aws.access_key_id = Some("AKIATEST".to_string());
+        new.aws.secret_access_key = Some("test-secret".to_string());
+        new.interval = HumanDuration::from_secs(60);
+        new.state.path = Some(PathBuf::from("/var/lib/auto-dns/state.json"));
+        assert_eq!(
+            old.diff(&new),
+            [
+                "record example.com: ttl: 300 -> 60",
+                "record new.example.com: added",
+                "aws.access_key_id: changed",
+                "aws.secret_access_key: changed",
+                "interval: 5m -> 1m",
+                "state.path: unset -> /var/lib/auto-dns/state.json",
+            ]
+        );
+        assert_eq!(new.diff(&old)[1], "record new.example.com: removed");
+
+        // Header values are API tokens, so they never show up in the log
+        let config = |token: &str, external_id: Option<&str>| {
+            let mut config = Config::default();
+            let mut service = HttpServiceConfig::plain("https://ip.example.com");
+            if let IpServiceConfig::Http(http) = &mut service {
+                http.headers
+                    .insert("Authorization".to_string(), format!("Bearer {token}"));
+            }
+            config.ip_detection.services = vec![service];
+            config.aws.assume_role.external_id = external_id.map(str::to_string);
+            config
+        };
+        let (old, new) = (
+            config("old-token", None),
+            config("new-token", Some("external-secret")),
+        );
+        let changes = old.diff(&new).join("\n");
+        assert_eq!(
+            changes,
+            "aws.external_id: changed\nip_detection.services: changed"
+        );
+        for secret in ["old-token", "new-token", "external-secret"] {
+            assert!(!changes.contains(secret), "{changes}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_concurrency() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(
+            temp_file,
+            "concurrency = 2
+[[records]]
+name = \"a.example.com\"
+"
+        )
+        .unwrap();
+        let config = Config::load(temp_file.path()).await.unwrap();
+        assert_eq!(config.concurrency, 2);
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(
+            temp_file,
+            "concurrency = 0
+[[records]]
+name = \"a.example.com\"
+"
+        )
+        .unwrap();
+        let err = Config::load(temp_file.path()).await.unwrap_err();
+        assert!(err.to_string().contains("Concurrency must be at least 1"));
     }
 }
@@ -1,13 +1,195 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use aws_config::sts::AssumeRoleProvider;
 use aws_config::{BehaviorVersion, Region};
 use aws_credential_types::{provider::SharedCredentialsProvider, Credentials};
-use aws_sdk_route53::types::{Change, ChangeAction, ResourceRecord, ResourceRecordSet, RrType};
+use aws_sdk_route53::config::http::HttpResponse;
+use aws_sdk_route53::error::{BoxError, BuildError, ProvideErrorMetadata, SdkError};
+use aws_sdk_route53::types::{
+    Change, ChangeAction, ChangeStatus, HostedZone, ResourceRecord, ResourceRecordSet, RrType,
+};
 use aws_sdk_route53::Client;
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use tracing::{debug, info};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::{AssumeRole, AwsConfig};
+use crate::ip::IpVersion;
+
+/// Why a Route53 operation failed, classified by what the caller should do about it.
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    /// The zone has no record of this name and type (yet)
+    #[error("No {record_type} record found for {name}")]
+    RecordNotFound {
+        name: String,
+        record_type: &'static str,
+    },
+    /// Route53 asked us to slow down, or an earlier change is still being applied
+    #[error("Route53 {operation} in zone {zone} was throttled")]
+    Throttled {
+        operation: &'static str,
+        zone: String,
+        #[source]
+        source: BoxError,
+    },
+    /// Network problems, timeouts and server-side errors
+    #[error("Route53 {operation} in zone {zone} failed")]
+    Transient {
+        operation: &'static str,
+        zone: String,
+        #[source]
+        source: BoxError,
+    },
+    /// The credentials are missing, invalid or lack permission; retrying will not help
+    #[error("Access denied for Route53 {operation} in zone {zone}; check the AWS credentials and IAM permissions")]
+    AccessDenied {
+        operation: &'static str,
+        zone: String,
+        #[source]
+        source: BoxError,
+    },
+    /// Any other error reported by Route53, e.g. a hosted zone that does not exist
+    #[error("Route53 rejected {operation} in zone {zone}")]
+    Rejected {
+        operation: &'static str,
+        zone: String,
+        #[source]
+        source: BoxError,
+    },
+    /// The change was accepted but had not reached all Route53 name servers in time
+    #[error("Route53 change {change_id} was still pending after {}s", waited.as_secs())]
+    SyncTimeout { change_id: String, waited: Duration },
+    #[error("No hosted zone found for {name}; set hosted_zone_id for this record")]
+    ZoneNotFound { name: String },
+    /// The closest zone by name is private or shares its name with other zones
+    #[error(
+        "Cannot pick a hosted zone for {name} from {zones}; set hosted_zone_id for this record"
+    )]
+    ZoneAmbiguous { name: String, zones: String },
+    #[error("Invalid IP in DNS record {name}: {value}")]
+    InvalidRecord { name: String, value: String },
+    #[error("Failed to build Route53 change")]
+    InvalidChange(#[from] BuildError),
+}
+
+impl DnsError {
+    /// Whether trying the same call again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DnsError::Throttled { .. } | DnsError::Transient { .. }
+        )
+    }
 
-use crate::config::AwsConfig;
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(self, DnsError::AccessDenied { .. })
+    }
+
+    /// Sorts an SDK failure of `operation` on `zone` into one of the variants above.
+    fn from_sdk<E>(operation: &'static str, zone: &str, err: SdkError<E, HttpResponse>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    {
+        let zone = zone.to_string();
+        let (code, server_error) = match &err {
+            SdkError::ServiceError(service) => (
+                service.err().code().map(str::to_string),
+                service.raw().status().is_server_error(),
+            ),
+            _ => (None, false),
+        };
+        let transport_failure = matches!(
+            err,
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_)
+        );
+        let source: BoxError = Box::new(err);
+
+        match code.as_deref() {
+            Some(
+                "Throttling"
+                | "ThrottlingException"
+                | "PriorRequestNotComplete"
+                | "RequestLimitExceeded",
+            ) => DnsError::Throttled {
+                operation,
+                zone,
+                source,
+            },
+            Some(
+                "AccessDenied"
+                | "AccessDeniedException"
+                | "InvalidClientTokenId"
+                | "UnrecognizedClientException"
+                | "SignatureDoesNotMatch"
+                | "IncompleteSignature"
+                | "MissingAuthenticationToken"
+                | "ExpiredToken"
+                | "NotAuthorized",
+            ) => DnsError::AccessDenied {
+                operation,
+                zone,
+                source,
+            },
+            _ if transport_failure || server_error => DnsError::Transient {
+                operation,
+                zone,
+                source,
+            },
+            _ => DnsError::Rejected {
+                operation,
+                zone,
+                source,
+            },
+        }
+    }
+}
+
+/// An address record to upsert as part of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordChange {
+    pub name: String,
+    pub ip: IpAddr,
+    pub ttl: i64,
+}
+
+/// Most changes submitted in one `ChangeBatch`. Route53 allows 1000 records and
+/// 32000 characters of values per request, counting each UPSERT twice, so this
+/// leaves room for the longest IPv6 addresses.
+pub const MAX_CHANGES_PER_BATCH: usize = 400;
+
+/// The address records of a hosted zone, listed in one go so that checking many
+/// records in the zone does not cost a `ListResourceRecordSets` call each.
+#[derive(Debug, Default)]
+pub struct ZoneRecords {
+    /// First value of each record, by normalized name and family
+    values: HashMap<(String, IpVersion), String>,
+}
+
+impl ZoneRecords {
+    /// Records the value of a listed record set. Later sets of the same name and type
+    /// (weighted, latency and similar records) do not replace the first one.
+    pub fn insert(&mut self, name: &str, version: IpVersion, value: &str) {
+        self.values
+            .entry((normalize_name(name), version))
+            .or_insert_with(|| value.to_string());
+    }
+
+    /// Answers like [`DnsOperations::get_current_record_ip`] would.
+    pub fn lookup(&self, record_name: &str, version: IpVersion) -> Result<IpAddr, DnsError> {
+        match self.values.get(&(normalize_name(record_name), version)) {
+            Some(value) => value.parse().map_err(|_| DnsError::InvalidRecord {
+                name: record_name.to_string(),
+                value: value.clone(),
+            }),
+            None => Err(DnsError::RecordNotFound {
+                name: record_name.to_string(),
+                record_type: version.record_type(),
+            }),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 pub trait DnsOperations {
@@ -15,19 +197,161 @@ pub trait DnsOperations {
         &self,
         hosted_zone_id: &str,
         record_name: &str,
-    ) -> Result<Ipv4Addr>;
+        version: IpVersion,
+    ) -> Result<IpAddr, DnsError>;
+
+    /// Lists all A and AAAA records of a zone, for checking several of its records at once.
+    async fn list_address_records(&self, hosted_zone_id: &str) -> Result<ZoneRecords, DnsError>;
 
-    async fn update_record(
+    /// How many `ListResourceRecordSets` calls [`DnsOperations::list_address_records`]
+    /// takes for the zone, judging by its number of record sets.
+    async fn listing_pages(&self, hosted_zone_id: &str) -> Result<usize, DnsError>;
+
+    /// Finds the ID of the public hosted zone whose name is the longest suffix of `record_name`.
+    async fn find_hosted_zone(&self, record_name: &str) -> Result<String, DnsError>;
+
+    /// Upserts all `changes` in one atomic batch, returning the ID of the submitted
+    /// change if Route53 reported one. Callers keep batches within [`MAX_CHANGES_PER_BATCH`].
+    async fn update_records(
         &self,
         hosted_zone_id: &str,
-        record_name: &str,
-        ip: &Ipv4Addr,
-        ttl: i64,
-    ) -> Result<()>;
+        changes: &[RecordChange],
+    ) -> Result<Option<String>, DnsError>;
+
+    /// Waits until `change_id` has reached all Route53 name servers (`INSYNC`)
+    /// and returns how long that took, giving up after `timeout`.
+    async fn wait_for_sync(
+        &self,
+        hosted_zone_id: &str,
+        change_id: &str,
+        timeout: Duration,
+    ) -> Result<Duration, DnsError>;
 }
 
+fn rr_type(version: IpVersion) -> RrType {
+    match version {
+        IpVersion::V4 => RrType::A,
+        IpVersion::V6 => RrType::Aaaa,
+    }
+}
+
+/// Record sets requested per `ListResourceRecordSets` page. Listing starts at the
+/// record itself, so more than one set is only needed for weighted, latency and
+/// similar records that share a name and type.
+const RECORD_SETS_PER_PAGE: i32 = 10;
+
+/// Delay before the first `GetChange` poll; doubled after every pending answer up to
+/// `SYNC_POLL_MAX_DELAY`. Changes usually reach all name servers within a minute.
+const SYNC_POLL_DELAY: Duration = Duration::from_secs(2);
+const SYNC_POLL_MAX_DELAY: Duration = Duration::from_secs(15);
+
+/// Record sets requested per page when listing a whole zone, the most Route53 allows
+const ZONE_LISTING_PAGE: i32 = 300;
+
+/// Hosted zones requested per `ListHostedZonesByName` page
+const HOSTED_ZONES_PER_PAGE: i32 = 10;
+
 pub struct DnsUpdater {
     client: Client,
+    /// Zone IDs found by [`DnsOperations::find_hosted_zone`], by record name
+    zone_cache: Mutex<HashMap<String, String>>,
+    /// Results of [`DnsOperations::listing_pages`] by zone ID. Zones rarely grow or
+    /// shrink by whole pages, and an outdated count only costs a few calls.
+    pages_cache: Mutex<HashMap<String, usize>>,
+}
+
+/// What a page of record sets, listed from our record onwards, says about it.
+#[derive(Debug, PartialEq)]
+enum PageLookup<'a> {
+    Found(&'a str),
+    /// The listing has moved past where the record would be
+    Missing,
+    /// Every set on the page is ours, but none holds a plain value (e.g. alias records)
+    KeepLooking,
+}
+
+fn lookup_in_page<'a>(
+    record_sets: &'a [ResourceRecordSet],
+    record_name: &str,
+    wanted_type: &RrType,
+) -> PageLookup<'a> {
+    for record_set in record_sets {
+        if !same_record_name(record_set.name(), record_name) || record_set.r#type() != wanted_type {
+            return PageLookup::Missing;
+        }
+        if let Some(first_record) = record_set.resource_records().first() {
+            return PageLookup::Found(first_record.value());
+        }
+    }
+    PageLookup::KeepLooking
+}
+
+/// Compares names the way Route53 does: case-insensitively, with or without the
+/// trailing dot, and with `*` listed as its `\052` escape.
+fn same_record_name(listed: &str, configured: &str) -> bool {
+    normalize_name(listed) == normalize_name(configured)
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.')
+        .replace("\\052", "*")
+        .to_ascii_lowercase()
+}
+
+/// The zones a record could live in, most specific first: `a.example.com`, then
+/// `example.com`, then `com`. Wildcard labels are skipped as no zone can be named after them.
+fn zone_candidates(record_name: &str) -> Vec<&str> {
+    let mut name = record_name.trim_end_matches('.');
+    let mut candidates = Vec::new();
+    loop {
+        if !name.starts_with('*') {
+            candidates.push(name);
+        }
+        match name.split_once('.') {
+            Some((_, parent)) => name = parent,
+            None => return candidates,
+        }
+    }
+}
+
+/// Picks the zone named `candidate` from a page of zones listed from that name onwards.
+/// `Ok(None)` means there is no such zone and the caller should try the parent domain.
+fn pick_zone(
+    zones: &[HostedZone],
+    candidate: &str,
+    record_name: &str,
+) -> Result<Option<String>, DnsError> {
+    let matching: Vec<&HostedZone> = zones
+        .iter()
+        .filter(|zone| same_record_name(zone.name(), candidate))
+        .collect();
+    let is_private = |zone: &HostedZone| zone.config().is_some_and(|c| c.private_zone());
+
+    match matching[..] {
+        [] => Ok(None),
+        [zone] if !is_private(zone) => Ok(Some(
+            zone.id().trim_start_matches("/hostedzone/").to_string(),
+        )),
+        _ => Err(DnsError::ZoneAmbiguous {
+            name: record_name.to_string(),
+            zones: matching
+                .iter()
+                .map(|zone| {
+                    let kind = if is_private(zone) {
+                        "private"
+                    } else {
+                        "public"
+                    };
+                    format!(
+                        "{} ({})",
+                        zone.id().trim_start_matches("/hostedzone/"),
+                        kind
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
 }
 
 #[async_trait::async_trait]
@@ -36,132 +360,357 @@ impl DnsOperations for DnsUpdater {
         &self,
         hosted_zone_id: &str,
         record_name: &str,
-    ) -> Result<Ipv4Addr> {
-        debug!("Getting current IP for record: {}", record_name);
+        version: IpVersion,
+    ) -> Result<IpAddr, DnsError> {
+        debug!(
+            "Getting current {} record for: {}",
+            version.record_type(),
+            record_name
+        );
+        let wanted_type = rr_type(version);
+        let not_found = || DnsError::RecordNotFound {
+            name: record_name.to_string(),
+            record_type: version.record_type(),
+        };
+
+        // Seek straight to the record instead of scanning the whole zone
+        let mut start_name = record_name.to_string();
+        let mut start_type = wanted_type.clone();
+        let mut start_identifier = None;
+        loop {
+            let response = self
+                .client
+                .list_resource_record_sets()
+                .hosted_zone_id(hosted_zone_id)
+                .start_record_name(&start_name)
+                .start_record_type(start_type)
+                .set_start_record_identifier(start_identifier)
+                .max_items(RECORD_SETS_PER_PAGE)
+                .send()
+                .await
+                .map_err(|e| DnsError::from_sdk("ListResourceRecordSets", hosted_zone_id, e))?;
+
+            match lookup_in_page(response.resource_record_sets(), record_name, &wanted_type) {
+                PageLookup::Found(value) => {
+                    return value.parse().map_err(|_| DnsError::InvalidRecord {
+                        name: record_name.to_string(),
+                        value: value.to_string(),
+                    })
+                }
+                PageLookup::Missing => return Err(not_found()),
+                PageLookup::KeepLooking => {}
+            }
+
+            match (response.next_record_name(), response.next_record_type()) {
+                (Some(name), Some(rtype)) if response.is_truncated() => {
+                    start_name = name.to_string();
+                    start_type = rtype.clone();
+                    start_identifier = response.next_record_identifier().map(str::to_string);
+                }
+                _ => return Err(not_found()),
+            }
+        }
+    }
+
+    async fn list_address_records(&self, hosted_zone_id: &str) -> Result<ZoneRecords, DnsError> {
+        debug!("Listing address records of zone {}", hosted_zone_id);
+        let mut records = ZoneRecords::default();
+        let mut start = None;
+        loop {
+            let (start_name, start_type, start_identifier) = match start.take() {
+                Some((name, rtype, identifier)) => (Some(name), Some(rtype), identifier),
+                None => (None, None, None),
+            };
+            let response = self
+                .client
+                .list_resource_record_sets()
+                .hosted_zone_id(hosted_zone_id)
+                .set_start_record_name(start_name)
+                .set_start_record_type(start_type)
+                .set_start_record_identifier(start_identifier)
+                .max_items(ZONE_LISTING_PAGE)
+                .send()
+                .await
+                .map_err(|e| DnsError::from_sdk("ListResourceRecordSets", hosted_zone_id, e))?;
+
+            for record_set in response.resource_record_sets() {
+                let version = match record_set.r#type() {
+                    RrType::A => IpVersion::V4,
+                    RrType::Aaaa => IpVersion::V6,
+                    _ => continue,
+                };
+                if let Some(first_record) = record_set.resource_records().first() {
+                    records.insert(record_set.name(), version, first_record.value());
+                }
+            }
+
+            match (response.next_record_name(), response.next_record_type()) {
+                (Some(name), Some(rtype)) if response.is_truncated() => {
+                    start = Some((
+                        name.to_string(),
+                        rtype.clone(),
+                        response.next_record_identifier().map(str::to_string),
+                    ));
+                }
+                _ => return Ok(records),
+            }
+        }
+    }
+
+    async fn listing_pages(&self, hosted_zone_id: &str) -> Result<usize, DnsError> {
+        if let Some(pages) = self.pages_cache.lock().unwrap().get(hosted_zone_id) {
+            return Ok(*pages);
+        }
 
         let response = self
             .client
-            .list_resource_record_sets()
-            .hosted_zone_id(hosted_zone_id)
+            .get_hosted_zone()
+            .id(hosted_zone_id)
             .send()
             .await
-            .with_context(|| format!("Failed to list records in zone {hosted_zone_id}"))?;
-
-        for record_set in response.resource_record_sets() {
-            let name = record_set.name();
-            let record_type = record_set.r#type();
-
-            if name.trim_end_matches('.') == record_name.trim_end_matches('.')
-                && *record_type == RrType::A
-            {
-                let records = record_set.resource_records();
-                if let Some(first_record) = records.first() {
-                    let value = first_record.value();
-                    return value
-                        .parse()
-                        .with_context(|| format!("Invalid IP in DNS record: {value}"));
+            .map_err(|e| DnsError::from_sdk("GetHostedZone", hosted_zone_id, e))?;
+        let record_sets = response
+            .hosted_zone()
+            .and_then(|zone| zone.resource_record_set_count())
+            .unwrap_or(0);
+        let pages = usize::try_from(record_sets)
+            .unwrap_or(0)
+            .div_ceil(ZONE_LISTING_PAGE as usize)
+            .max(1);
+        debug!(
+            "Zone {} has {} record sets, {} page(s) to list",
+            hosted_zone_id, record_sets, pages
+        );
+        self.pages_cache
+            .lock()
+            .unwrap()
+            .insert(hosted_zone_id.to_string(), pages);
+        Ok(pages)
+    }
+
+    async fn find_hosted_zone(&self, record_name: &str) -> Result<String, DnsError> {
+        let key = record_name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(zone_id) = self.zone_cache.lock().unwrap().get(&key) {
+            return Ok(zone_id.clone());
+        }
+
+        for candidate in zone_candidates(record_name) {
+            // Zones are listed in name order starting at `candidate`, so any zones of
+            // that name come first; more than a page of them only when truncated
+            let mut zones = Vec::new();
+            let mut next_zone_id = None;
+            loop {
+                let response = self
+                    .client
+                    .list_hosted_zones_by_name()
+                    .dns_name(candidate)
+                    .set_hosted_zone_id(next_zone_id)
+                    .max_items(HOSTED_ZONES_PER_PAGE)
+                    .send()
+                    .await
+                    .map_err(|e| DnsError::from_sdk("ListHostedZonesByName", candidate, e))?;
+                zones.extend_from_slice(response.hosted_zones());
+
+                match (response.next_dns_name(), response.next_hosted_zone_id()) {
+                    (Some(name), Some(zone_id))
+                        if response.is_truncated() && same_record_name(name, candidate) =>
+                    {
+                        next_zone_id = Some(zone_id.to_string());
+                    }
+                    _ => break,
                 }
             }
+
+            if let Some(zone_id) = pick_zone(&zones, candidate, record_name)? {
+                info!(
+                    "Using hosted zone {} ({}) for {}",
+                    zone_id, candidate, record_name
+                );
+                self.zone_cache.lock().unwrap().insert(key, zone_id.clone());
+                return Ok(zone_id);
+            }
         }
 
-        anyhow::bail!("No A record found for {}", record_name)
+        Err(DnsError::ZoneNotFound {
+            name: record_name.to_string(),
+        })
     }
 
-    async fn update_record(
+    async fn update_records(
         &self,
         hosted_zone_id: &str,
-        record_name: &str,
-        ip: &Ipv4Addr,
-        ttl: i64,
-    ) -> Result<()> {
-        info!("Updating DNS record {} to {}", record_name, ip);
-
-        let record_name = if record_name.ends_with('.') {
-            record_name.to_string()
-        } else {
-            format!("{record_name}.")
-        };
-
-        let resource_record = ResourceRecord::builder()
-            .value(ip.to_string())
-            .build()
-            .context("Failed to build resource record")?;
-
-        let record_set = ResourceRecordSet::builder()
-            .name(&record_name)
-            .r#type(RrType::A)
-            .ttl(ttl)
-            .resource_records(resource_record)
-            .build()
-            .context("Failed to build resource record set")?;
-
-        let change = Change::builder()
-            .action(ChangeAction::Upsert)
-            .resource_record_set(record_set)
-            .build()
-            .context("Failed to build change")?;
+        changes: &[RecordChange],
+    ) -> Result<Option<String>, DnsError> {
+        let mut batch = aws_sdk_route53::types::ChangeBatch::builder()
+            .comment(format!("Updated by auto-dns at {}", chrono::Utc::now()));
+        for change in changes {
+            info!("Updating DNS record {} to {}", change.name, change.ip);
+
+            let record_name = if change.name.ends_with('.') {
+                change.name.clone()
+            } else {
+                format!("{}.", change.name)
+            };
+
+            let resource_record = ResourceRecord::builder()
+                .value(change.ip.to_string())
+                .build()?;
+
+            let record_set = ResourceRecordSet::builder()
+                .name(record_name)
+                .r#type(rr_type(IpVersion::of(&change.ip)))
+                .ttl(change.ttl)
+                .resource_records(resource_record)
+                .build()?;
+
+            batch = batch.changes(
+                Change::builder()
+                    .action(ChangeAction::Upsert)
+                    .resource_record_set(record_set)
+                    .build()?,
+            );
+        }
 
         let response = self
             .client
             .change_resource_record_sets()
             .hosted_zone_id(hosted_zone_id)
-            .change_batch(
-                aws_sdk_route53::types::ChangeBatch::builder()
-                    .changes(change)
-                    .comment(format!("Updated by auto-dns at {}", chrono::Utc::now()))
-                    .build()
-                    .context("Failed to build change batch")?,
-            )
+            .change_batch(batch.build()?)
             .send()
             .await
-            .with_context(|| {
-                format!("Failed to update DNS record {record_name} in zone {hosted_zone_id}")
-            })?;
+            .map_err(|e| DnsError::from_sdk("ChangeResourceRecordSets", hosted_zone_id, e))?;
 
-        if let Some(change_info) = response.change_info() {
-            debug!("Change submitted with ID: {:?}", change_info.id());
-        }
+        let change_id = response.change_info().map(|info| info.id().to_string());
+        debug!("Change submitted with ID: {:?}", change_id);
 
-        Ok(())
+        Ok(change_id)
     }
-}
 
-impl DnsUpdater {
-    pub async fn new(aws_config: &AwsConfig) -> Result<Self> {
-        let credentials = Credentials::new(
-            &aws_config.access_key_id,
-            &aws_config.secret_access_key,
-            None,
-            None,
-            "auto-dns",
-        );
+    async fn wait_for_sync(
+        &self,
+        hosted_zone_id: &str,
+        change_id: &str,
+        timeout: Duration,
+    ) -> Result<Duration, DnsError> {
+        let started = Instant::now();
+        let mut delay = SYNC_POLL_DELAY;
+        loop {
+            let response = self
+                .client
+                .get_change()
+                .id(change_id)
+                .send()
+                .await
+                .map_err(|e| DnsError::from_sdk("GetChange", hosted_zone_id, e));
+            let in_sync = match response {
+                Ok(response) => response
+                    .change_info()
+                    .is_some_and(|info| *info.status() == ChangeStatus::Insync),
+                // Polling asks again anyway, so a throttled or failed poll only costs time
+                Err(e) if e.is_retryable() => {
+                    warn!("Could not check change {}: {}", change_id, e);
+                    false
+                }
+                Err(e) => return Err(e),
+            };
+
+            let waited = started.elapsed();
+            if in_sync {
+                return Ok(waited);
+            }
+            if waited + delay > timeout {
+                return Err(DnsError::SyncTimeout {
+                    change_id: change_id.to_string(),
+                    waited,
+                });
+            }
 
-        let region = Region::new(aws_config.region());
+            debug!(
+                "Change {} still pending, checking again in {:?}",
+                change_id, delay
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(SYNC_POLL_MAX_DELAY);
+        }
+    }
+}
 
-        let config = aws_config::defaults(BehaviorVersion::latest())
-            .region(region)
-            .credentials_provider(SharedCredentialsProvider::new(credentials))
-            .load()
-            .await;
+impl DnsUpdater {
+    /// Connects with the credentials from `aws_config`, or with `role` assumed through
+    /// them. Assumed credentials are refreshed by the SDK before they expire.
+    pub async fn new(aws_config: &AwsConfig, role: Option<&AssumeRole>) -> Result<Self> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &aws_config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(profile) = &aws_config.profile {
+            loader = loader.profile_name(profile);
+        }
+        if let Some((access_key_id, secret_access_key)) = aws_config.static_credentials() {
+            let credentials =
+                Credentials::new(access_key_id, secret_access_key, None, None, "auto-dns");
+            loader = loader.credentials_provider(SharedCredentialsProvider::new(credentials));
+        }
+        let mut config = loader.load().await;
+        // Route53 is a global service, so the region only picks the API endpoint, for
+        // Route53 and for STS when a role is assumed
+        let region = config
+            .region()
+            .cloned()
+            .unwrap_or_else(|| Region::from_static("us-east-1"));
+
+        if let Some(role) = role {
+            let mut provider = AssumeRoleProvider::builder(&role.role_arn)
+                .session_name(&role.session_name)
+                .region(region.clone())
+                .configure(&config);
+            if let Some(external_id) = &role.external_id {
+                provider = provider.external_id(external_id);
+            }
+            config = config
+                .into_builder()
+                .credentials_provider(SharedCredentialsProvider::new(provider.build().await))
+                .build();
+        }
 
-        let client = Client::new(&config);
+        // Calls are retried as `[retry]` says, so the SDK must not retry them as well
+        let mut route53_config = aws_sdk_route53::config::Builder::from(&config)
+            .retry_config(aws_config::retry::RetryConfig::disabled())
+            .region(region);
+        if let Some(endpoint_url) = &aws_config.endpoint_url {
+            info!("Using Route53 endpoint {}", endpoint_url);
+            route53_config = route53_config.endpoint_url(endpoint_url);
+        }
+        let client = Client::from_conf(route53_config.build());
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            zone_cache: Mutex::new(HashMap::new()),
+            pages_cache: Mutex::new(HashMap::new()),
+        })
     }
 }
 
 pub struct MockDnsUpdater {
-    // Unused field for now but could be used for more sophisticated simulation
-    #[allow(dead_code)]
-    simulated_records: HashMap<String, Ipv4Addr>,
+    // Records "updated" during this run, so later lookups see the simulated change
+    simulated_records: Mutex<HashMap<(String, IpVersion), IpAddr>>,
 }
 
 impl MockDnsUpdater {
     pub fn new() -> Self {
         Self {
-            simulated_records: HashMap::new(),
+            simulated_records: Mutex::new(HashMap::new()),
         }
     }
+
+    #[cfg(test)]
+    pub fn simulated_ip(&self, record_name: &str, version: IpVersion) -> Option<IpAddr> {
+        self.simulated_records
+            .lock()
+            .unwrap()
+            .get(&(record_name.to_string(), version))
+            .copied()
+    }
 }
 
 #[async_trait::async_trait]
@@ -170,37 +719,91 @@ impl DnsOperations for MockDnsUpdater {
         &self,
         hosted_zone_id: &str,
         record_name: &str,
-    ) -> Result<Ipv4Addr> {
+        version: IpVersion,
+    ) -> Result<IpAddr, DnsError> {
         info!(
-            "[DRY RUN] Getting current IP for record: {} in zone {}",
-            record_name, hosted_zone_id
+            "[DRY RUN] Getting current {} record for: {} in zone {}",
+            version.record_type(),
+            record_name,
+            hosted_zone_id
         );
 
         // Simulate a different IP to trigger updates in dry run mode
-        let simulated_ip = "192.168.1.100".parse().unwrap();
+        let simulated_ip = match self
+            .simulated_records
+            .lock()
+            .unwrap()
+            .get(&(record_name.to_string(), version))
+        {
+            Some(ip) => *ip,
+            None => match version {
+                IpVersion::V4 => "192.168.1.100".parse().unwrap(),
+                IpVersion::V6 => "fd00::100".parse().unwrap(),
+            },
+        };
         info!("[DRY RUN] Simulated current DNS IP: {}", simulated_ip);
 
         Ok(simulated_ip)
     }
 
-    async fn update_record(
-        &self,
-        hosted_zone_id: &str,
-        record_name: &str,
-        ip: &Ipv4Addr,
-        ttl: i64,
-    ) -> Result<()> {
+    async fn list_address_records(&self, hosted_zone_id: &str) -> Result<ZoneRecords, DnsError> {
         info!(
-            "[DRY RUN] Would update DNS record {} in zone {} to {} with TTL {}",
-            record_name, hosted_zone_id, ip, ttl
+            "[DRY RUN] Listing address records of zone {}",
+            hosted_zone_id
         );
-        info!("[DRY RUN] AWS Route53 API call would be made to change_resource_record_sets");
+        // Only the records "updated" so far exist; everything else is created
+        let mut records = ZoneRecords::default();
+        for ((name, version), ip) in self.simulated_records.lock().unwrap().iter() {
+            records.insert(name, *version, &ip.to_string());
+        }
+        Ok(records)
+    }
+
+    async fn listing_pages(&self, hosted_zone_id: &str) -> Result<usize, DnsError> {
         info!(
-            "[DRY RUN] Change would be: UPSERT A record {} -> {}",
-            record_name, ip
+            "[DRY RUN] Would count the records of zone {} with get_hosted_zone",
+            hosted_zone_id
         );
+        Ok(1)
+    }
 
-        Ok(())
+    async fn find_hosted_zone(&self, record_name: &str) -> Result<String, DnsError> {
+        info!(
+            "[DRY RUN] Would look up the hosted zone for {} with list_hosted_zones_by_name",
+            record_name
+        );
+        Ok("ZDRYRUN".to_string())
+    }
+
+    async fn update_records(
+        &self,
+        hosted_zone_id: &str,
+        changes: &[RecordChange],
+    ) -> Result<Option<String>, DnsError> {
+        let mut simulated_records = self.simulated_records.lock().unwrap();
+        for change in changes {
+            info!(
+                "[DRY RUN] Would update DNS record {} in zone {} to {} with TTL {}",
+                change.name, hosted_zone_id, change.ip, change.ttl
+            );
+            simulated_records.insert((change.name.clone(), IpVersion::of(&change.ip)), change.ip);
+        }
+        info!(
+            "[DRY RUN] AWS Route53 API call would be made to change_resource_record_sets with {} change(s)",
+            changes.len()
+        );
+
+        Ok(Some("DRY-RUN".to_string()))
+    }
+
+    async fn wait_for_sync(
+        &self,
+        _hosted_zone_id: &str,
+        change_id: &str,
+        _timeout: Duration,
+    ) -> Result<Duration, DnsError> {
+        info!("[DRY RUN] Would wait for change {} to be INSYNC", change_id);
+        Ok(Duration::ZERO)
     }
 }
 
@@ -208,6 +811,146 @@ impl DnsOperations for MockDnsUpdater {
 mod tests {
     use super::*;
 
+    fn record_set(name: &str, rtype: RrType, value: Option<&str>) -> ResourceRecordSet {
+        let mut builder = ResourceRecordSet::builder().name(name).r#type(rtype);
+        if let Some(value) = value {
+            builder = builder
+                .ttl(300)
+                .resource_records(ResourceRecord::builder().value(value).build().unwrap());
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn test_lookup_in_page() {
+        let found = [
+            record_set("home.example.com.", RrType::A, Some("81.2.69.160")),
+            record_set("office.example.com.", RrType::A, Some("81.2.69.161")),
+        ];
+        assert_eq!(
+            lookup_in_page(&found, "Home.Example.com", &RrType::A),
+            PageLookup::Found("81.2.69.160")
+        );
+
+        // Listing starts at the next record in Route53's order when ours is missing
+        assert_eq!(
+            lookup_in_page(&found[1..], "home.example.com", &RrType::A),
+            PageLookup::Missing
+        );
+        assert_eq!(
+            lookup_in_page(&found, "home.example.com", &RrType::Aaaa),
+            PageLookup::Missing
+        );
+        assert_eq!(
+            lookup_in_page(&[], "home.example.com", &RrType::A),
+            PageLookup::KeepLooking
+        );
+
+        let alias_then_plain = [
+            record_set("home.example.com.", RrType::A, None),
+            record_set("home.example.com.", RrType::A, Some("81.2.69.162")),
+        ];
+        assert_eq!(
+            lookup_in_page(&alias_then_plain[..1], "home.example.com", &RrType::A),
+            PageLookup::KeepLooking
+        );
+        assert_eq!(
+            lookup_in_page(&alias_then_plain, "home.example.com", &RrType::A),
+            PageLookup::Found("81.2.69.162")
+        );
+
+        let wildcard = [record_set(
+            "\\052.example.com.",
+            RrType::A,
+            Some("81.2.69.160"),
+        )];
+        assert_eq!(
+            lookup_in_page(&wildcard, "*.example.com", &RrType::A),
+            PageLookup::Found("81.2.69.160")
+        );
+    }
+
+    #[test]
+    fn test_zone_records() {
+        let mut records = ZoneRecords::default();
+        records.insert("Home.Example.com.", IpVersion::V4, "81.2.69.160");
+        records.insert("home.example.com.", IpVersion::V4, "81.2.69.161");
+        records.insert("\\052.example.com.", IpVersion::V6, "2001:db8::1");
+        records.insert("broken.example.com.", IpVersion::V4, "not-an-ip");
+
+        // The first of several sets with the same name and type wins
+        assert_eq!(
+            records.lookup("home.example.com", IpVersion::V4).unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            records.lookup("*.example.com", IpVersion::V6).unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        assert!(matches!(
+            records.lookup("home.example.com", IpVersion::V6),
+            Err(DnsError::RecordNotFound { .. })
+        ));
+        assert!(matches!(
+            records.lookup("broken.example.com", IpVersion::V4),
+            Err(DnsError::InvalidRecord { .. })
+        ));
+    }
+
+    fn hosted_zone(id: &str, name: &str, private: bool) -> HostedZone {
+        HostedZone::builder()
+            .id(format!("/hostedzone/{id}"))
+            .name(name)
+            .caller_reference(id)
+            .config(
+                aws_sdk_route53::types::HostedZoneConfig::builder()
+                    .private_zone(private)
+                    .build(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_zone_candidates() {
+        assert_eq!(
+            zone_candidates("home.lab.example.com."),
+            [
+                "home.lab.example.com",
+                "lab.example.com",
+                "example.com",
+                "com"
+            ]
+        );
+        assert_eq!(zone_candidates("*.example.com"), ["example.com", "com"]);
+    }
+
+    #[test]
+    fn test_pick_zone() {
+        let record = "home.example.com";
+        let public = hosted_zone("Z1PUBLIC", "example.com.", false);
+        let private = hosted_zone("Z2PRIVATE", "example.com.", true);
+        let later = hosted_zone("Z3OTHER", "example.net.", false);
+
+        assert_eq!(
+            pick_zone(&[public.clone(), later.clone()], "example.com", record).unwrap(),
+            Some("Z1PUBLIC".to_string())
+        );
+        // The listing starts after where the zone would be, so try the parent
+        assert_eq!(pick_zone(&[later], "example.com", record).unwrap(), None);
+
+        let err = pick_zone(&[public.clone(), private.clone()], "example.com", record).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot pick a hosted zone for home.example.com from Z1PUBLIC (public), \
+             Z2PRIVATE (private); set hosted_zone_id for this record"
+        );
+        let err = pick_zone(&[private], "example.com", record).unwrap_err();
+        assert!(matches!(err, DnsError::ZoneAmbiguous { .. }));
+        let err = pick_zone(&[public.clone(), public], "example.com", record).unwrap_err();
+        assert!(matches!(err, DnsError::ZoneAmbiguous { .. }));
+    }
+
     // Note: These tests require AWS credentials and would modify real DNS records
     // In a real project, you'd want to use mocks or a test environment
 
@@ -216,27 +959,29 @@ mod tests {
     async fn test_dns_operations() {
         use crate::config::AwsConfig;
 
-        let aws_config = AwsConfig {
-            access_key_id: "test-access-key".to_string(),
-            secret_access_key: "test-secret-key".to_string(),
-        };
+        let aws_config = AwsConfig::default();
 
-        let updater = DnsUpdater::new(&aws_config).await.unwrap();
+        let updater = DnsUpdater::new(&aws_config, None).await.unwrap();
 
         // These values should be replaced with actual test zone/record
         let test_zone_id = "Z1234567890ABC";
         let test_record = "test.example.com";
-        let test_ip: Ipv4Addr = "1.2.3.4".parse().unwrap();
+        let test_ip: IpAddr = "1.2.3.4".parse().unwrap();
 
         // Test updating a record
+        let change = RecordChange {
+            name: test_record.to_string(),
+            ip: test_ip,
+            ttl: 300,
+        };
         updater
-            .update_record(test_zone_id, test_record, &test_ip, 300)
+            .update_records(test_zone_id, &[change])
             .await
             .unwrap();
 
         // Test getting the record back
         let retrieved_ip = updater
-            .get_current_record_ip(test_zone_id, test_record)
+            .get_current_record_ip(test_zone_id, test_record, IpVersion::V4)
             .await
             .unwrap();
 
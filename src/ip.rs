@@ -1,60 +1,567 @@
 use anyhow::{Context, Result};
-use std::net::Ipv4Addr;
+use futures::future::join_all;
+use ipnet::IpNet;
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
 use std::str::FromStr;
-use tracing::{debug, warn};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::config::{
+    AddressScope, CommandSourceConfig, ConsensusConfig, DetectionMode, DnsQueryType,
+    DnsServiceConfig, FileSourceConfig, GatewayServiceConfig, HttpServiceConfig,
+    InterfaceSourceConfig, IpDetectionConfig, IpServiceConfig, IpSourceConfig, ResponseFormat,
+    RetryConfig, StaticSourceConfig, StunServiceConfig,
+};
+use crate::retry;
+
+/// Address family of a public IP / DNS record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub fn of(ip: &IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => IpVersion::V4,
+            IpAddr::V6(_) => IpVersion::V6,
+        }
+    }
+
+    /// The DNS record type holding addresses of this family.
+    pub fn record_type(self) -> &'static str {
+        match self {
+            IpVersion::V4 => "A",
+            IpVersion::V6 => "AAAA",
+        }
+    }
+
+    pub fn matches(self, ip: &IpAddr) -> bool {
+        IpVersion::of(ip) == self
+    }
+}
+
+impl fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpVersion::V4 => write!(f, "IPv4"),
+            IpVersion::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Why a source could not provide an address.
+#[derive(Debug, thiserror::Error)]
+pub enum IpError {
+    /// Every detection service failed or gave an unacceptable answer
+    #[error("Failed to detect public {version} address from any service")]
+    Unavailable {
+        version: IpVersion,
+        /// Some service failed in a way that may go away, such as a timeout
+        transient: bool,
+    },
+    #[error(
+        "No {version} address reported by at least {min_agree} of {queried} services: {answers}"
+    )]
+    NoConsensus {
+        version: IpVersion,
+        min_agree: usize,
+        queried: usize,
+        answers: String,
+        transient: bool,
+    },
+    #[error(
+        "Conflicting {version} addresses each reported by at least {min_agree} services: {answers}"
+    )]
+    Conflict {
+        version: IpVersion,
+        min_agree: usize,
+        answers: String,
+    },
+    /// The source is configured without an address of this family
+    #[error("No {version} address configured for {from}")]
+    NoAddress { from: String, version: IpVersion },
+    /// A local source (interface, file or command) could not be read
+    #[error("Failed to get address from {from}")]
+    Source {
+        from: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Invalid detection service {service}")]
+    InvalidService {
+        service: String,
+        #[source]
+        source: BoxError,
+    },
+    #[error("Failed to create HTTP client")]
+    Client(#[from] reqwest::Error),
+}
+
+impl IpError {
+    /// Whether asking the services again might give a different outcome.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            IpError::Unavailable {
+                transient: true,
+                ..
+            } | IpError::NoConsensus {
+                transient: true,
+                ..
+            }
+        )
+    }
+}
+
+/// A detection service answered with an unsuccessful HTTP status.
+#[derive(Debug, thiserror::Error)]
+#[error("HTTP error {status}: {url}")]
+struct HttpStatusError {
+    status: reqwest::StatusCode,
+    url: String,
+}
+
+/// Whether a service failed because of something that may go away on its own:
+/// a timeout, a refused or reset connection, or a server error or rate limit.
+fn is_transient(error: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            e.is_timeout() || e.is_connect()
+        } else if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            e.status.is_server_error() || e.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        } else if cause.is::<tokio::time::error::Elapsed>() {
+            true
+        } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::NetworkUnreachable
+                    | ErrorKind::HostUnreachable
+            )
+        } else {
+            false
+        }
+    })
+}
+
+/// Somewhere to learn the address that should be published in DNS.
+#[async_trait::async_trait]
+pub trait IpSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError>;
+
+    /// Short human-readable description for logs
+    fn describe(&self) -> String;
+}
+
+/// Builds the source described by `source`; `detection` configures the detection
+/// services and `retry` how often a failed detection round is repeated.
+pub fn source_from_config(
+    source: &IpSourceConfig,
+    detection: &IpDetectionConfig,
+    retry: &RetryConfig,
+) -> Result<Box<dyn IpSource>, IpError> {
+    Ok(match source {
+        IpSourceConfig::Services => Box::new(IpDetector::new(detection, retry)?),
+        IpSourceConfig::Interface(interface) => Box::new(InterfaceSource::new(interface)),
+        IpSourceConfig::Static(addresses) => Box::new(StaticSource::new(addresses)),
+        IpSourceConfig::File(file) => Box::new(FileSource::new(file)),
+        IpSourceConfig::Command(command) => Box::new(CommandSource::new(command)),
+    })
+}
 
 pub struct IpDetector {
     client: reqwest::Client,
-    services: Vec<&'static str>,
+    services: Vec<Service>,
+    services_v6: Vec<Service>,
+    mode: DetectionMode,
+    consensus: ConsensusConfig,
+    retry: RetryConfig,
+}
+
+/// Result of a consensus round: the accepted address plus who disagreed with it.
+#[derive(Debug)]
+pub struct ConsensusOutcome {
+    pub address: IpAddr,
+    pub agreeing: Vec<String>,
+    /// Services that answered with another address or failed, with their answer or error
+    pub dissenters: Vec<(String, String)>,
+}
+
+/// A configured detection service.
+struct Service {
+    name: String,
+    /// Non-public networks whose addresses are accepted from this service anyway
+    allow: Vec<IpNet>,
+    protocol: Protocol,
+}
+
+enum Protocol {
+    Http(HttpService),
+    Stun(StunService),
+    Dns(DnsService),
+    Upnp(UpnpService),
+    NatPmp(NatPmpService),
+    Pcp(PcpService),
+}
+
+impl Service {
+    fn from_config(config: &IpServiceConfig) -> Result<Self, IpError> {
+        let protocol = match config {
+            IpServiceConfig::Http(http) => {
+                Protocol::Http(HttpService::from_config(http).map_err(|e| {
+                    IpError::InvalidService {
+                        service: config.name(),
+                        source: e.into(),
+                    }
+                })?)
+            }
+            IpServiceConfig::Stun(stun) => Protocol::Stun(StunService::from_config(stun)),
+            IpServiceConfig::Dns(dns) => Protocol::Dns(DnsService::from_config(dns)),
+            IpServiceConfig::Upnp(upnp) => Protocol::Upnp(UpnpService {
+                location: upnp.location.clone(),
+                timeout: Duration::from_secs(upnp.timeout),
+            }),
+            IpServiceConfig::NatPmp(gateway) => Protocol::NatPmp(NatPmpService {
+                gateway: GatewayService::from_config(gateway),
+            }),
+            IpServiceConfig::Pcp(gateway) => Protocol::Pcp(PcpService {
+                gateway: GatewayService::from_config(gateway),
+            }),
+        };
+
+        Ok(Self {
+            name: config.name(),
+            allow: config.allow().to_vec(),
+            protocol,
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+struct HttpService {
+    url: String,
+    timeout: Duration,
+    headers: HeaderMap,
+    parser: ResponseParser,
+}
+
+enum ResponseParser {
+    Text,
+    Json(Vec<String>),
+    Regex(Regex),
+}
+
+impl HttpService {
+    fn from_config(config: &HttpServiceConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name '{name}' for {}", config.url))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header '{name}' for {}", config.url))?;
+            headers.insert(name, value);
+        }
+
+        let parser = match &config.format {
+            ResponseFormat::Text => ResponseParser::Text,
+            ResponseFormat::Json { field } => {
+                ResponseParser::Json(field.split('.').map(str::to_string).collect())
+            }
+            ResponseFormat::Regex { pattern } => ResponseParser::Regex(
+                Regex::new(pattern).with_context(|| format!("Invalid regex for {}", config.url))?,
+            ),
+        };
+
+        Ok(Self {
+            url: config.url.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            headers,
+            parser,
+        })
+    }
+}
+
+impl ResponseParser {
+    /// Pulls the address text out of a response body.
+    fn extract<'a>(&self, body: &'a str) -> Result<Cow<'a, str>> {
+        match self {
+            ResponseParser::Text => Ok(Cow::Borrowed(body.trim())),
+            ResponseParser::Json(path) => {
+                let document: serde_json::Value =
+                    serde_json::from_str(body).context("Response is not valid JSON")?;
+
+                let mut value = &document;
+                for key in path {
+                    value = match value {
+                        serde_json::Value::Array(items) => {
+                            key.parse::<usize>().ok().and_then(|i| items.get(i))
+                        }
+                        _ => value.get(key),
+                    }
+                    .with_context(|| format!("Field '{}' not found in response", path.join(".")))?;
+                }
+
+                value
+                    .as_str()
+                    .map(|ip| Cow::Owned(ip.trim().to_string()))
+                    .with_context(|| format!("Field '{}' is not a string", path.join(".")))
+            }
+            ResponseParser::Regex(regex) => {
+                let captures = regex
+                    .captures(body)
+                    .with_context(|| format!("Pattern '{regex}' did not match response"))?;
+                let matched = captures.get(1).or_else(|| captures.get(0)).unwrap();
+                Ok(Cow::Borrowed(matched.as_str().trim()))
+            }
+        }
+    }
 }
 
 impl IpDetector {
-    pub fn new() -> Self {
-        let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(10))
-            .build()
-            .expect("Failed to create HTTP client");
+    pub fn new(config: &IpDetectionConfig, retry: &RetryConfig) -> Result<Self, IpError> {
+        let client = reqwest::Client::builder().build()?;
+
+        let services = config
+            .services
+            .iter()
+            .map(Service::from_config)
+            .collect::<Result<_, _>>()?;
+        let services_v6 = config
+            .services_v6
+            .iter()
+            .map(Service::from_config)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            client,
+            services,
+            services_v6,
+            mode: config.mode,
+            consensus: config.consensus,
+            retry: *retry,
+        })
+    }
+
+    /// Detects the address, repeating the whole round if every service failed and
+    /// at least one of them did so for a reason that may go away.
+    pub async fn get_public_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        let what = format!("Detecting public {version} address");
+        retry::with_retries(&self.retry, &what, IpError::is_retryable, || {
+            self.detect(version)
+        })
+        .await
+    }
 
-        let services = vec![
-            "https://api.ipify.org",
-            "https://icanhazip.com",
-            "https://ifconfig.me/ip",
-            "https://checkip.amazonaws.com",
-            "https://ipecho.net/plain",
-        ];
+    async fn detect(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        match self.mode {
+            DetectionMode::Failover => self.get_first_answer(version).await,
+            DetectionMode::Consensus => {
+                let outcome = self.get_consensus(version).await?;
+                debug!(
+                    "Consensus on {} address {} from {}",
+                    version,
+                    outcome.address,
+                    outcome.agreeing.join(", ")
+                );
+                for (service, answer) in &outcome.dissenters {
+                    warn!(
+                        "{} detection service {} disagreed with consensus {}: {}",
+                        version, service, outcome.address, answer
+                    );
+                }
+                Ok(outcome.address)
+            }
+        }
+    }
 
-        Self { client, services }
+    fn services(&self, version: IpVersion) -> &[Service] {
+        match version {
+            IpVersion::V4 => &self.services,
+            IpVersion::V6 => &self.services_v6,
+        }
     }
 
-    pub async fn get_public_ip(&self) -> Result<Ipv4Addr> {
-        for (i, service) in self.services.iter().enumerate() {
-            debug!("Trying IP detection service {}: {}", i + 1, service);
+    async fn get_first_answer(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        let mut transient = false;
+        for (i, service) in self.services(version).iter().enumerate() {
+            debug!(
+                "Trying {} detection service {}: {}",
+                version,
+                i + 1,
+                service.name()
+            );
 
-            match self.fetch_ip_from_service(service).await {
+            match self.fetch_ip_from_service(service, version).await {
                 Ok(ip) => {
-                    debug!("Successfully got IP {} from {}", ip, service);
+                    debug!("Successfully got IP {} from {}", ip, service.name());
                     return Ok(ip);
                 }
                 Err(e) => {
-                    warn!("Failed to get IP from {}: {}", service, e);
-                    continue;
+                    warn!("Failed to get IP from {}: {:#}", service.name(), e);
+                    transient |= is_transient(&e);
                 }
             }
         }
 
-        anyhow::bail!("Failed to detect public IP from any service")
+        Err(IpError::Unavailable { version, transient })
+    }
+
+    /// Queries the first `consensus.queries` services concurrently and accepts an
+    /// address only if at least `consensus.min_agree` of them report it.
+    pub async fn get_consensus(&self, version: IpVersion) -> Result<ConsensusOutcome, IpError> {
+        let services = self.services(version);
+        let queried = &services[..self.consensus.queries.min(services.len())];
+        if queried.len() < self.consensus.queries {
+            warn!(
+                "Only {} {} detection services configured, consensus wants {}",
+                queried.len(),
+                version,
+                self.consensus.queries
+            );
+        }
+
+        debug!(
+            "Querying {} {} detection services for consensus",
+            queried.len(),
+            version
+        );
+        let answers = join_all(
+            queried
+                .iter()
+                .map(|service| self.fetch_ip_from_service(service, version)),
+        )
+        .await;
+
+        let mut votes: HashMap<IpAddr, Vec<&str>> = HashMap::new();
+        for (service, answer) in queried.iter().zip(&answers) {
+            if let Ok(ip) = answer {
+                votes.entry(*ip).or_default().push(service.name());
+            }
+        }
+
+        let winners: Vec<_> = votes
+            .iter()
+            .filter(|(_, voters)| voters.len() >= self.consensus.min_agree)
+            .collect();
+
+        let summary = || {
+            queried
+                .iter()
+                .zip(&answers)
+                .map(|(service, answer)| match answer {
+                    Ok(ip) => format!("{}={}", service.name(), ip),
+                    Err(e) => format!("{}=error ({:#})", service.name(), e),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let (&address, agreeing) = match winners.as_slice() {
+            [winner] => *winner,
+            [] => {
+                return Err(IpError::NoConsensus {
+                    version,
+                    min_agree: self.consensus.min_agree,
+                    queried: queried.len(),
+                    answers: summary(),
+                    transient: answers
+                        .iter()
+                        .any(|answer| answer.as_ref().is_err_and(is_transient)),
+                })
+            }
+            _ => {
+                return Err(IpError::Conflict {
+                    version,
+                    min_agree: self.consensus.min_agree,
+                    answers: summary(),
+                })
+            }
+        };
+
+        let dissenters = queried
+            .iter()
+            .zip(&answers)
+            .filter_map(|(service, answer)| match answer {
+                Ok(ip) if *ip == address => None,
+                Ok(ip) => Some((service.name().to_string(), ip.to_string())),
+                Err(e) => Some((service.name().to_string(), format!("{e:#}"))),
+            })
+            .collect();
+
+        Ok(ConsensusOutcome {
+            address,
+            agreeing: agreeing.iter().map(|url| url.to_string()).collect(),
+            dissenters,
+        })
+    }
+
+    /// Asks one service for the address, rejecting answers that cannot be our
+    /// public address unless the service's allowlist covers them.
+    async fn fetch_ip_from_service(&self, service: &Service, version: IpVersion) -> Result<IpAddr> {
+        let ip = match &service.protocol {
+            Protocol::Http(http) => self.fetch_ip_from_http(http, version).await,
+            Protocol::Stun(stun) => stun.get_mapped_address(version).await,
+            Protocol::Dns(dns) => dns.query_own_address(version).await,
+            Protocol::Upnp(upnp) => upnp.get_external_address(&self.client, version).await,
+            Protocol::NatPmp(natpmp) => natpmp.get_external_address(version).await,
+            Protocol::Pcp(pcp) => pcp.get_external_address(version).await,
+        }?;
+
+        if let Some(range) = non_public_range(&ip) {
+            if !service.allow.iter().any(|net| net.contains(&ip)) {
+                anyhow::bail!("Rejected {} address {} from {}", range, ip, service.name());
+            }
+            debug!(
+                "Accepting {} address {} from {} (allowlisted)",
+                range,
+                ip,
+                service.name()
+            );
+        }
+
+        Ok(ip)
     }
 
-    async fn fetch_ip_from_service(&self, url: &str) -> Result<Ipv4Addr> {
+    async fn fetch_ip_from_http(
+        &self,
+        service: &HttpService,
+        version: IpVersion,
+    ) -> Result<IpAddr> {
+        let url = &service.url;
         let response = self
             .client
             .get(url)
+            .headers(service.headers.clone())
+            .timeout(service.timeout)
             .send()
             .await
             .with_context(|| format!("Failed to make request to {url}"))?;
 
         if !response.status().is_success() {
-            anyhow::bail!("HTTP error {}: {}", response.status(), url);
+            return Err(HttpStatusError {
+                status: response.status(),
+                url: url.clone(),
+            }
+            .into());
         }
 
         let text = response
@@ -62,34 +569,1879 @@ impl IpDetector {
             .await
             .with_context(|| format!("Failed to read response from {url}"))?;
 
-        let ip_str = text.trim();
-        let ip = Ipv4Addr::from_str(ip_str)
-            .with_context(|| format!("Invalid IP address '{ip_str}' from {url}"))?;
+        let ip_str = service
+            .parser
+            .extract(&text)
+            .with_context(|| format!("Unexpected response from {url}"))?;
+
+        parse_ip(&ip_str, version).with_context(|| format!("Invalid response from {url}"))
+    }
+}
+
+/// Learns the mapped public address with an RFC 5389 binding request over UDP.
+struct StunService {
+    server: String,
+    timeout: Duration,
+}
+
+const STUN_DEFAULT_PORT: u16 = 3478;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+impl StunService {
+    fn from_config(config: &StunServiceConfig) -> Self {
+        Self {
+            server: config.server.clone(),
+            timeout: Duration::from_secs(config.timeout),
+        }
+    }
+
+    async fn get_mapped_address(&self, version: IpVersion) -> Result<IpAddr> {
+        let server = resolve_server(&self.server, STUN_DEFAULT_PORT, version).await?;
+        let transaction_id: [u8; 12] = rand::random();
+        let request = stun_binding_request(&transaction_id);
+
+        let ip = udp_exchange(server, &request, self.timeout, |response| {
+            parse_stun_response(response, &transaction_id)
+        })
+        .await
+        .with_context(|| format!("STUN request to {server} failed"))?;
+
+        if !version.matches(&ip) {
+            anyhow::bail!("Expected an {} address but got '{}'", version, ip);
+        }
 
         Ok(ip)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn stun_binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes()); // no attributes
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
 
-    #[tokio::test]
-    async fn test_get_public_ip() {
-        let detector = IpDetector::new();
-        let result = detector.get_public_ip().await;
+/// Extracts the (XOR-)MAPPED-ADDRESS from a binding success response.
+fn parse_stun_response(packet: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr> {
+    if packet.len() < 20 {
+        anyhow::bail!("STUN message too short ({} bytes)", packet.len());
+    }
 
-        // This test depends on network connectivity, so we'll just check
-        // that it either succeeds or fails gracefully
-        match result {
-            Ok(ip) => {
-                println!("Detected IP: {}", ip);
-                assert!(!ip.is_loopback());
-                assert!(!ip.is_private());
+    let message_type = u16::from_be_bytes([packet[0], packet[1]]);
+    let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if packet[4..8] != STUN_MAGIC_COOKIE.to_be_bytes() || packet[8..20] != transaction_id[..] {
+        anyhow::bail!("STUN response does not match our request");
+    }
+    if message_type != STUN_BINDING_SUCCESS {
+        anyhow::bail!("Unexpected STUN message type {:#06x}", message_type);
+    }
+
+    let attributes = packet
+        .get(20..20 + length)
+        .context("STUN message length exceeds packet size")?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let len = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes
+            .get(offset + 4..offset + 4 + len)
+            .context("STUN attribute exceeds message size")?;
+
+        match kind {
+            STUN_ATTR_XOR_MAPPED_ADDRESS => {
+                return decode_stun_address(value, Some(transaction_id));
             }
-            Err(e) => {
-                println!("IP detection failed (this is OK in CI): {}", e);
+            STUN_ATTR_MAPPED_ADDRESS => mapped = Some(decode_stun_address(value, None)?),
+            _ => {}
+        }
+
+        // Attributes are padded to a multiple of four bytes
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+
+    mapped.context("STUN response has no mapped address")
+}
+
+/// Decodes a MAPPED-ADDRESS value, or an XOR-MAPPED-ADDRESS one when the transaction ID is given.
+fn decode_stun_address(value: &[u8], xor_with: Option<&[u8; 12]>) -> Result<IpAddr> {
+    let mut key = [0u8; 16];
+    if let Some(transaction_id) = xor_with {
+        key[..4].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+    }
+
+    let family = *value.get(1).context("STUN address attribute too short")?;
+    let address = match family {
+        0x01 => value.get(4..8),
+        0x02 => value.get(4..20),
+        _ => anyhow::bail!("Unknown STUN address family {:#04x}", family),
+    }
+    .context("STUN address attribute too short")?;
+
+    let mut octets = [0u8; 16];
+    for (i, byte) in address.iter().enumerate() {
+        octets[i] = byte ^ key[i];
+    }
+
+    Ok(match family {
+        0x01 => IpAddr::from([octets[0], octets[1], octets[2], octets[3]]),
+        _ => IpAddr::from(octets),
+    })
+}
+
+/// Asks a DNS server for a name whose answer is the querying host's own address.
+struct DnsService {
+    resolver: String,
+    query: String,
+    record_type: Option<DnsQueryType>,
+    timeout: Duration,
+}
+
+const DNS_DEFAULT_PORT: u16 = 53;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_TXT: u16 = 16;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_CLASS_IN: u16 = 1;
+
+impl DnsService {
+    fn from_config(config: &DnsServiceConfig) -> Self {
+        Self {
+            resolver: config.resolver.clone(),
+            query: config.query.clone(),
+            record_type: config.record_type,
+            timeout: Duration::from_secs(config.timeout),
+        }
+    }
+
+    async fn query_own_address(&self, version: IpVersion) -> Result<IpAddr> {
+        let qtype = match (self.record_type, version) {
+            (Some(DnsQueryType::A), _) | (None, IpVersion::V4) => DNS_TYPE_A,
+            (Some(DnsQueryType::Aaaa), _) | (None, IpVersion::V6) => DNS_TYPE_AAAA,
+            (Some(DnsQueryType::Txt), _) => DNS_TYPE_TXT,
+        };
+
+        // The resolver is reached over the same family we want to learn
+        let resolver = resolve_server(&self.resolver, DNS_DEFAULT_PORT, version).await?;
+        let id: u16 = rand::random();
+        let request = dns_query(id, &self.query, qtype)?;
+
+        let answers = udp_exchange(resolver, &request, self.timeout, |response| {
+            parse_dns_response(response, id, qtype)
+        })
+        .await
+        .with_context(|| format!("DNS query for {} to {} failed", self.query, resolver))?;
+
+        answers
+            .iter()
+            .filter_map(|answer| IpAddr::from_str(answer.trim()).ok())
+            .find(|ip| version.matches(ip))
+            .with_context(|| {
+                format!(
+                    "No {} address in answer for {}: {:?}",
+                    version, self.query, answers
+                )
+            })
+    }
+}
+
+fn dns_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(32 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // standard query, recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question, no other records
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid DNS name: {name}");
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Returns the answers of type `qtype` as text: addresses for A/AAAA, joined strings for TXT.
+fn parse_dns_response(packet: &[u8], id: u16, qtype: u16) -> Result<Vec<String>> {
+    let header = packet.get(..12).context("DNS response too short")?;
+    let read_u16 = |at: usize| -> Result<u16> {
+        packet
+            .get(at..at + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .context("DNS response truncated")
+    };
+
+    if u16::from_be_bytes([header[0], header[1]]) != id || header[2] & 0x80 == 0 {
+        anyhow::bail!("DNS response does not match our query");
+    }
+    let rcode = header[3] & 0x0f;
+    if rcode != 0 {
+        anyhow::bail!("DNS server returned error code {}", rcode);
+    }
+
+    let questions = read_u16(4)?;
+    let answer_count = read_u16(6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_dns_name(packet, offset)? + 4; // QTYPE and QCLASS
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..answer_count {
+        offset = skip_dns_name(packet, offset)?;
+        let rtype = read_u16(offset)?;
+        let rdlength = read_u16(offset + 8)? as usize;
+        let rdata = packet
+            .get(offset + 10..offset + 10 + rdlength)
+            .context("DNS response truncated")?;
+        offset += 10 + rdlength;
+
+        // Skip CNAMEs and anything else that is not what we asked for
+        if rtype != qtype {
+            continue;
+        }
+        match rtype {
+            DNS_TYPE_A if rdlength == 4 => {
+                answers.push(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]]).to_string())
+            }
+            DNS_TYPE_AAAA if rdlength == 16 => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                answers.push(IpAddr::from(octets).to_string())
+            }
+            DNS_TYPE_TXT => {
+                // TXT data is a sequence of length-prefixed character strings
+                let mut text = String::new();
+                let mut at = 0;
+                while let Some(&len) = rdata.get(at) {
+                    let chunk = rdata
+                        .get(at + 1..at + 1 + len as usize)
+                        .context("DNS TXT record truncated")?;
+                    text.push_str(&String::from_utf8_lossy(chunk));
+                    at += 1 + len as usize;
+                }
+                answers.push(text);
+            }
+            _ => anyhow::bail!("Malformed DNS record of type {}", rtype),
+        }
+    }
+
+    if answers.is_empty() {
+        anyhow::bail!("DNS response has no answers");
+    }
+
+    Ok(answers)
+}
+
+/// Returns the offset just past the (possibly compressed) name starting at `offset`.
+fn skip_dns_name(packet: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = *packet.get(offset).context("DNS response truncated")?;
+        match len {
+            0 => return Ok(offset + 1),
+            // A compression pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// Asks the local UPnP Internet Gateway Device for its external address.
+struct UpnpService {
+    location: Option<String>,
+    timeout: Duration,
+}
+
+const SSDP_MULTICAST: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+const IGD_DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const IGD_CONNECTION_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+impl UpnpService {
+    async fn get_external_address(
+        &self,
+        client: &reqwest::Client,
+        version: IpVersion,
+    ) -> Result<IpAddr> {
+        if version == IpVersion::V6 {
+            anyhow::bail!("UPnP IGD gateways only report IPv4 addresses");
+        }
+
+        let location = match &self.location {
+            Some(location) => location.clone(),
+            None => discover_igd(SSDP_MULTICAST, self.timeout).await?,
+        };
+
+        let description = client
+            .get(&location)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Failed to fetch UPnP device description from {location}"))?
+            .text()
+            .await
+            .with_context(|| format!("Failed to read UPnP device description from {location}"))?;
+        let (control_url, service_type) = parse_igd_description(&description, &location)?;
+
+        let envelope = format!(
+            r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:GetExternalIPAddress xmlns:u="{service_type}"/></s:Body></s:Envelope>"#
+        );
+        let response = client
+            .post(control_url.clone())
+            .header("Content-Type", r#"text/xml; charset="utf-8""#)
+            .header(
+                "SOAPAction",
+                format!(r#""{service_type}#GetExternalIPAddress""#),
+            )
+            .body(envelope)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .with_context(|| format!("Failed to make UPnP request to {control_url}"))?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("UPnP error {}: {}", response.status(), control_url);
+        }
+
+        let text = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read response from {control_url}"))?;
+        let ip_str = xml_element(&text, "NewExternalIPAddress")
+            .with_context(|| format!("No external address in response from {control_url}"))?;
+
+        let ip = parse_ip(&ip_str, version)
+            .with_context(|| format!("Invalid response from {control_url}"))?;
+        if ip.is_unspecified() {
+            anyhow::bail!("Gateway at {} has no external address", control_url);
+        }
+
+        Ok(ip)
+    }
+}
+
+/// Finds an Internet Gateway Device with an SSDP M-SEARCH and returns its description URL.
+async fn discover_igd(target: SocketAddr, timeout: Duration) -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("Failed to bind SSDP socket")?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {target}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {IGD_DEVICE_TYPE}\r\n\r\n"
+    );
+
+    let discovery = async {
+        let mut buf = [0u8; 1500];
+        loop {
+            socket.send_to(request.as_bytes(), target).await?;
+
+            // Responses come from the gateway itself, not the multicast address
+            let (len, from) =
+                match tokio::time::timeout(UDP_RETRANSMIT_INTERVAL, socket.recv_from(&mut buf))
+                    .await
+                {
+                    Ok(received) => received?,
+                    Err(_) => continue,
+                };
+
+            match ssdp_location(&String::from_utf8_lossy(&buf[..len])) {
+                Some(location) => {
+                    debug!("Found UPnP gateway {} at {}", location, from);
+                    return Ok::<_, anyhow::Error>(location);
+                }
+                None => debug!("Ignoring SSDP response from {} without a location", from),
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, discovery)
+        .await
+        .with_context(|| format!("No UPnP gateway answered discovery within {timeout:?}"))?
+}
+
+fn ssdp_location(response: &str) -> Option<String> {
+    response
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("location"))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Finds the WAN connection service in an IGD device description, returning
+/// its absolute control URL and service type.
+fn parse_igd_description(description: &str, location: &str) -> Result<(reqwest::Url, String)> {
+    let base = xml_element(description, "URLBase").unwrap_or_else(|| location.to_string());
+    let base =
+        reqwest::Url::parse(&base).with_context(|| format!("Invalid UPnP base URL '{base}'"))?;
+
+    let services = Regex::new(r"(?s)<service>(.*?)</service>").unwrap();
+    for service in services.captures_iter(description) {
+        let service = &service[1];
+        let Some(service_type) = xml_element(service, "serviceType") else {
+            continue;
+        };
+        if !IGD_CONNECTION_SERVICES
+            .iter()
+            .any(|prefix| service_type.starts_with(prefix))
+        {
+            continue;
+        }
+
+        let control = xml_element(service, "controlURL")
+            .with_context(|| format!("{service_type} has no control URL"))?;
+        let control_url = base
+            .join(&control)
+            .with_context(|| format!("Invalid control URL '{control}'"))?;
+        return Ok((control_url, service_type));
+    }
+
+    anyhow::bail!("No WAN connection service in device description from {location}")
+}
+
+/// The text of the first `tag` element, whatever its namespace prefix.
+fn xml_element(xml: &str, tag: &str) -> Option<String> {
+    let tag = regex::escape(tag);
+    let pattern = format!(r"(?s)<(?:\w+:)?{tag}(?:\s[^>]*)?>\s*([^<]*?)\s*</(?:\w+:)?{tag}>");
+    let captures = Regex::new(&pattern).ok()?.captures(xml)?;
+    Some(captures[1].to_string())
+}
+
+/// Where to reach a NAT-PMP/PCP server, shared by both protocols.
+struct GatewayService {
+    gateway: Option<String>,
+    timeout: Duration,
+}
+
+const NAT_PMP_PORT: u16 = 5351;
+
+impl GatewayService {
+    fn from_config(config: &GatewayServiceConfig) -> Self {
+        Self {
+            gateway: config.gateway.clone(),
+            timeout: Duration::from_secs(config.timeout),
+        }
+    }
+
+    /// The configured gateway, or the IPv4 default gateway from the routing table.
+    async fn address(&self, version: IpVersion) -> Result<SocketAddr> {
+        match &self.gateway {
+            Some(gateway) => resolve_server(gateway, NAT_PMP_PORT, version).await,
+            None if version == IpVersion::V4 => {
+                let routes = tokio::fs::read_to_string("/proc/net/route")
+                    .await
+                    .context("Failed to read the routing table; set the gateway explicitly")?;
+                let gateway = default_gateway(&routes).context("No IPv4 default gateway")?;
+                Ok(SocketAddr::new(gateway.into(), NAT_PMP_PORT))
             }
+            None => anyhow::bail!("The {} gateway must be configured explicitly", version),
+        }
+    }
+}
+
+/// Parses the default route's gateway out of Linux's `/proc/net/route`.
+fn default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway, mask) = (fields.get(1)?, fields.get(2)?, fields.get(7)?);
+        if *destination != "00000000" || *mask != "00000000" {
+            return None;
+        }
+
+        // The kernel prints the network-order address as a native integer
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        (gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Asks a NAT-PMP gateway (RFC 6886) for its external address.
+struct NatPmpService {
+    gateway: GatewayService,
+}
+
+const NAT_PMP_EXTERNAL_ADDRESS_REQUEST: [u8; 2] = [0, 0];
+const NAT_PMP_EXTERNAL_ADDRESS_RESPONSE: u8 = 128;
+
+impl NatPmpService {
+    async fn get_external_address(&self, version: IpVersion) -> Result<IpAddr> {
+        if version == IpVersion::V6 {
+            anyhow::bail!("NAT-PMP gateways only report IPv4 addresses");
         }
+
+        let gateway = self.gateway.address(version).await?;
+        let ip = udp_exchange(
+            gateway,
+            &NAT_PMP_EXTERNAL_ADDRESS_REQUEST,
+            self.gateway.timeout,
+            parse_nat_pmp_response,
+        )
+        .await
+        .with_context(|| format!("NAT-PMP request to {gateway} failed"))?;
+
+        if ip.is_unspecified() {
+            anyhow::bail!("NAT-PMP gateway {} has no external address", gateway);
+        }
+
+        Ok(ip)
+    }
+}
+
+fn parse_nat_pmp_response(packet: &[u8]) -> Result<IpAddr> {
+    if packet.len() < 12 {
+        anyhow::bail!("NAT-PMP response too short ({} bytes)", packet.len());
+    }
+    if packet[0] != 0 || packet[1] != NAT_PMP_EXTERNAL_ADDRESS_RESPONSE {
+        anyhow::bail!("NAT-PMP response does not match our request");
+    }
+
+    let result = u16::from_be_bytes([packet[2], packet[3]]);
+    if result != 0 {
+        anyhow::bail!("NAT-PMP gateway returned result code {}", result);
+    }
+
+    Ok(IpAddr::from([packet[8], packet[9], packet[10], packet[11]]))
+}
+
+/// Learns the external address from the answer to a short-lived PCP (RFC 6887)
+/// MAP request, which is released again straight away.
+struct PcpService {
+    gateway: GatewayService,
+}
+
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE_BIT: u8 = 0x80;
+const PCP_PROTOCOL_UDP: u8 = 17;
+const PCP_MAPPING_LIFETIME: u32 = 60;
+
+impl PcpService {
+    async fn get_external_address(&self, version: IpVersion) -> Result<IpAddr> {
+        let gateway = self.gateway.address(version).await?;
+        let socket = udp_connect(gateway).await?;
+        let local = socket
+            .local_addr()
+            .context("Failed to read local UDP address")?;
+
+        let nonce: [u8; 12] = rand::random();
+        let request = pcp_map_request(&nonce, local, PCP_MAPPING_LIFETIME);
+        let ip = udp_exchange_on(&socket, &request, self.gateway.timeout, |response| {
+            parse_pcp_map_response(response, &nonce)
+        })
+        .await
+        .with_context(|| format!("PCP request to {gateway} failed"))?;
+
+        // Only the address was wanted, so give the mapping back (best effort; it expires anyway)
+        let release = pcp_map_request(&nonce, local, 0);
+        if let Err(e) = socket.send(&release).await {
+            debug!("Failed to release PCP mapping on {}: {}", gateway, e);
+        }
+
+        if ip.is_unspecified() {
+            anyhow::bail!("PCP gateway {} has no external address", gateway);
+        }
+        if !version.matches(&ip) {
+            anyhow::bail!("Expected an {} address but got '{}'", version, ip);
+        }
+
+        Ok(ip)
+    }
+}
+
+/// Builds a MAP request for UDP traffic to `client`'s port.
+fn pcp_map_request(nonce: &[u8; 12], client: SocketAddr, lifetime: u32) -> Vec<u8> {
+    let (client_ip, any) = match client.ip() {
+        IpAddr::V4(ip) => (ip.to_ipv6_mapped(), Ipv4Addr::UNSPECIFIED.to_ipv6_mapped()),
+        IpAddr::V6(ip) => (ip, Ipv6Addr::UNSPECIFIED),
+    };
+
+    let mut request = Vec::with_capacity(60);
+    request.extend_from_slice(&[PCP_VERSION, PCP_OPCODE_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&client_ip.octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[PCP_PROTOCOL_UDP, 0, 0, 0]);
+    request.extend_from_slice(&client.port().to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes()); // no suggested external port
+    request.extend_from_slice(&any.octets()); // no suggested external address
+    request
+}
+
+/// Extracts the assigned external address from a MAP response.
+fn parse_pcp_map_response(packet: &[u8], nonce: &[u8; 12]) -> Result<IpAddr> {
+    if packet.len() < 24 {
+        anyhow::bail!("PCP response too short ({} bytes)", packet.len());
+    }
+    if packet[0] != PCP_VERSION || packet[1] != PCP_RESPONSE_BIT | PCP_OPCODE_MAP {
+        anyhow::bail!("PCP response does not match our request");
+    }
+    if packet[3] != 0 {
+        anyhow::bail!("PCP gateway returned result code {}", packet[3]);
+    }
+
+    let opcode_data = packet.get(24..60).context("PCP MAP response too short")?;
+    if opcode_data[..12] != nonce[..] {
+        anyhow::bail!("PCP response does not match our request");
+    }
+
+    let octets: [u8; 16] = opcode_data[20..36].try_into().unwrap();
+    let external = Ipv6Addr::from(octets);
+    Ok(match external.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(external),
+    })
+}
+
+/// Resolves `host[:port]` to an address of the requested family.
+async fn resolve_server(server: &str, default_port: u16, version: IpVersion) -> Result<SocketAddr> {
+    let target = if server.parse::<SocketAddr>().is_ok() {
+        server.to_string()
+    } else if let Ok(ip) = server.parse::<IpAddr>() {
+        SocketAddr::new(ip, default_port).to_string()
+    } else if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{server}:{default_port}")
+    };
+
+    let mut addresses = tokio::net::lookup_host(&target)
+        .await
+        .with_context(|| format!("Failed to resolve {target}"))?;
+    addresses
+        .find(|addr| version.matches(&addr.ip()))
+        .with_context(|| format!("{target} has no {version} address"))
+}
+
+const UDP_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sends `request` to `server` over UDP, resending it until `parse` accepts a
+/// response or `timeout` expires. Responses `parse` rejects are ignored.
+async fn udp_exchange<T>(
+    server: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+    parse: impl Fn(&[u8]) -> Result<T>,
+) -> Result<T> {
+    let socket = udp_connect(server).await?;
+    udp_exchange_on(&socket, request, timeout, parse).await
+}
+
+/// Binds a UDP socket of `server`'s family and connects it to `server`.
+async fn udp_connect(server: SocketAddr) -> Result<UdpSocket> {
+    let version = IpVersion::of(&server.ip());
+    let local: SocketAddr = match version {
+        IpVersion::V4 => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpVersion::V6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .with_context(|| format!("Failed to bind {version} UDP socket"))?;
+    socket
+        .connect(server)
+        .await
+        .with_context(|| format!("Failed to connect to {server}"))?;
+    Ok(socket)
+}
+
+/// Like [`udp_exchange`], on a socket already connected to the server.
+async fn udp_exchange_on<T>(
+    socket: &UdpSocket,
+    request: &[u8],
+    timeout: Duration,
+    parse: impl Fn(&[u8]) -> Result<T>,
+) -> Result<T> {
+    let server = socket.peer_addr().context("UDP socket is not connected")?;
+
+    let exchange = async {
+        let mut buf = [0u8; 1500];
+        loop {
+            socket.send(request).await?;
+
+            // UDP is lossy, so resend the request until the overall timeout expires
+            let len =
+                match tokio::time::timeout(UDP_RETRANSMIT_INTERVAL, socket.recv(&mut buf)).await {
+                    Ok(received) => received?,
+                    Err(_) => continue,
+                };
+
+            match parse(&buf[..len]) {
+                Ok(answer) => return Ok::<_, anyhow::Error>(answer),
+                Err(e) => debug!("Ignoring response from {}: {:#}", server, e),
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .with_context(|| format!("{server} did not answer within {timeout:?}"))?
+}
+
+#[async_trait::async_trait]
+impl IpSource for IpDetector {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        self.get_public_ip(version).await
+    }
+
+    fn describe(&self) -> String {
+        "detection services".to_string()
+    }
+}
+
+/// Reads the address assigned to a local network interface.
+pub struct InterfaceSource {
+    config: InterfaceSourceConfig,
+}
+
+impl InterfaceSource {
+    pub fn new(config: &InterfaceSourceConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    /// Picks the first address that passes the configured filters.
+    fn select(&self, addresses: &[IpAddr], version: IpVersion) -> Result<IpAddr> {
+        for ip in addresses {
+            if !version.matches(ip) {
+                continue;
+            }
+            if self.config.scope == AddressScope::Global && (ip.is_loopback() || is_link_local(ip))
+            {
+                debug!(
+                    "Skipping non-global address {} on {}",
+                    ip, self.config.interface
+                );
+                continue;
+            }
+            if self.config.exclude_private && is_private(ip) {
+                debug!(
+                    "Skipping private address {} on {}",
+                    ip, self.config.interface
+                );
+                continue;
+            }
+            if let Some(prefix) = &self.config.prefix {
+                if !prefix.contains(ip) {
+                    debug!(
+                        "Skipping address {} outside {} on {}",
+                        ip, prefix, self.config.interface
+                    );
+                    continue;
+                }
+            }
+
+            return Ok(*ip);
+        }
+
+        anyhow::bail!(
+            "No suitable {} address on interface {}",
+            version,
+            self.config.interface
+        )
+    }
+}
+
+impl InterfaceSource {
+    fn read(&self, version: IpVersion) -> Result<IpAddr> {
+        let name = &self.config.interface;
+        let interfaces = if_addrs::get_if_addrs().context("Failed to list network interfaces")?;
+
+        let addresses: Vec<IpAddr> = interfaces
+            .iter()
+            .filter(|interface| interface.name == *name)
+            .map(|interface| interface.ip())
+            .collect();
+        if addresses.is_empty() {
+            anyhow::bail!("Interface {} not found or has no addresses", name);
+        }
+
+        self.select(&addresses, version)
+    }
+}
+
+#[async_trait::async_trait]
+impl IpSource for InterfaceSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        self.read(version).map_err(|e| IpError::Source {
+            from: self.describe(),
+            source: e.into(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("interface {}", self.config.interface)
+    }
+}
+
+/// Publishes fixed addresses, for hosts whose address never changes.
+pub struct StaticSource {
+    addresses: Vec<IpAddr>,
+}
+
+impl StaticSource {
+    pub fn new(config: &StaticSourceConfig) -> Self {
+        Self {
+            addresses: config.addresses.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl IpSource for StaticSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        self.addresses
+            .iter()
+            .find(|ip| version.matches(ip))
+            .copied()
+            .ok_or_else(|| IpError::NoAddress {
+                from: self.describe(),
+                version,
+            })
+    }
+
+    fn describe(&self) -> String {
+        "static addresses".to_string()
+    }
+}
+
+/// Reads the address from a file containing whitespace-separated addresses.
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(config: &FileSourceConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+        }
+    }
+}
+
+impl FileSource {
+    async fn read(&self, version: IpVersion) -> Result<IpAddr> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("Failed to read IP file: {}", self.path.display()))?;
+
+        first_address(&content, version)
+            .with_context(|| format!("Invalid IP file: {}", self.path.display()))
+    }
+}
+
+#[async_trait::async_trait]
+impl IpSource for FileSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        self.read(version).await.map_err(|e| IpError::Source {
+            from: self.describe(),
+            source: e.into(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+/// Runs an external program and reads the address from its standard output.
+pub struct CommandSource {
+    config: CommandSourceConfig,
+}
+
+impl CommandSource {
+    pub fn new(config: &CommandSourceConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+}
+
+impl CommandSource {
+    async fn run(&self, version: IpVersion) -> Result<IpAddr> {
+        let command = &self.config.command;
+        let output = tokio::time::timeout(
+            Duration::from_secs(self.config.timeout),
+            tokio::process::Command::new(command)
+                .args(&self.config.args)
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .with_context(|| format!("Command {command} timed out"))?
+        .with_context(|| format!("Failed to run command {command}"))?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "Command {} failed with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        first_address(&String::from_utf8_lossy(&output.stdout), version)
+            .with_context(|| format!("Invalid output from command {command}"))
+    }
+}
+
+#[async_trait::async_trait]
+impl IpSource for CommandSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        self.run(version).await.map_err(|e| IpError::Source {
+            from: self.describe(),
+            source: e.into(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("command {}", self.config.command)
+    }
+}
+
+/// Stands in for real detection in dry-run mode and tests, without touching the network.
+pub struct MockIpSource {
+    pub v4: IpAddr,
+    pub v6: IpAddr,
+}
+
+impl MockIpSource {
+    pub fn new() -> Self {
+        Self {
+            v4: "203.0.113.10".parse().unwrap(),
+            v6: "2001:db8::10".parse().unwrap(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl IpSource for MockIpSource {
+    async fn get_ip(&self, version: IpVersion) -> Result<IpAddr, IpError> {
+        let ip = match version {
+            IpVersion::V4 => self.v4,
+            IpVersion::V6 => self.v6,
+        };
+        info!("[DRY RUN] Simulated public {} address: {}", version, ip);
+
+        Ok(ip)
+    }
+
+    fn describe(&self) -> String {
+        "simulated addresses".to_string()
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    }
+}
+
+/// RFC 1918, CGNAT (100.64.0.0/10) and unique local IPv6 addresses.
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64),
+        IpAddr::V6(ip) => ip.is_unique_local(),
+    }
+}
+
+/// Names the special-purpose range `ip` belongs to, if it is not a globally
+/// routable unicast address that could be our public one.
+fn non_public_range(ip: &IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            if ip.is_unspecified() || a == 0 {
+                Some("unspecified")
+            } else if ip.is_loopback() {
+                Some("loopback")
+            } else if ip.is_private() {
+                Some("private")
+            } else if a == 100 && b & 0xc0 == 64 {
+                Some("CGNAT")
+            } else if ip.is_link_local() {
+                Some("link-local")
+            } else if ip.is_documentation() {
+                Some("documentation")
+            } else if ip.is_multicast() {
+                Some("multicast")
+            } else if (a, b, c) == (192, 0, 0) || (a == 198 && b & 0xfe == 18) || a >= 240 {
+                // IETF protocol assignments, benchmarking, reserved and broadcast
+                Some("reserved")
+            } else {
+                None
+            }
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if ip.is_unspecified() {
+                Some("unspecified")
+            } else if ip.is_loopback() {
+                Some("loopback")
+            } else if ip.is_unique_local() {
+                Some("private")
+            } else if ip.is_unicast_link_local() {
+                Some("link-local")
+            } else if segments[0] == 0x2001 && segments[1] == 0x0db8 {
+                Some("documentation")
+            } else if ip.is_multicast() {
+                Some("multicast")
+            } else if segments[0] & 0xe000 != 0x2000 {
+                // Outside 2000::/3, e.g. IPv4-mapped or discard-only addresses
+                Some("reserved")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Finds the first address of the requested family in whitespace-separated text.
+fn first_address(text: &str, version: IpVersion) -> Result<IpAddr> {
+    text.split_whitespace()
+        .filter_map(|word| IpAddr::from_str(word).ok())
+        .find(|ip| version.matches(ip))
+        .with_context(|| format!("No {version} address found"))
+}
+
+/// Parses `ip_str` and checks that it belongs to the requested address family.
+fn parse_ip(ip_str: &str, version: IpVersion) -> Result<IpAddr> {
+    let ip = IpAddr::from_str(ip_str).with_context(|| format!("Invalid IP address '{ip_str}'"))?;
+
+    if !version.matches(&ip) {
+        anyhow::bail!("Expected an {} address but got '{}'", version, ip);
+    }
+
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::UpnpServiceConfig;
+    use crate::test_util::{dns_response, question_name, stun_response, HttpStub, UdpStub};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    fn http(url: &str, format: ResponseFormat) -> HttpServiceConfig {
+        HttpServiceConfig {
+            url: url.to_string(),
+            timeout: 2,
+            headers: BTreeMap::new(),
+            format,
+            allow: Vec::new(),
+        }
+    }
+
+    fn service(url: &str, format: ResponseFormat) -> IpServiceConfig {
+        IpServiceConfig::Http(http(url, format))
+    }
+
+    /// One attempt only, so failures show up right away
+    const NO_RETRY: RetryConfig = RetryConfig {
+        max_attempts: 1,
+        base_delay_ms: 1,
+        max_delay_ms: 1,
+        jitter: false,
+    };
+
+    fn detector(services: Vec<IpServiceConfig>) -> IpDetector {
+        IpDetector::new(
+            &IpDetectionConfig {
+                services,
+                services_v6: Vec::new(),
+                mode: DetectionMode::Failover,
+                consensus: ConsensusConfig::default(),
+            },
+            &NO_RETRY,
+        )
+        .unwrap()
+    }
+
+    fn consensus_detector(
+        services: Vec<IpServiceConfig>,
+        queries: usize,
+        min_agree: usize,
+    ) -> IpDetector {
+        IpDetector::new(
+            &IpDetectionConfig {
+                services,
+                services_v6: Vec::new(),
+                mode: DetectionMode::Consensus,
+                consensus: ConsensusConfig { queries, min_agree },
+            },
+            &NO_RETRY,
+        )
+        .unwrap()
+    }
+
+    async fn text_stubs(bodies: &[&str]) -> (Vec<HttpStub>, Vec<IpServiceConfig>) {
+        let mut stubs = Vec::new();
+        for body in bodies {
+            stubs.push(HttpStub::start(200, body).await);
+        }
+        let services = stubs
+            .iter()
+            .map(|stub| service(&stub.url(), ResponseFormat::Text))
+            .collect();
+        (stubs, services)
+    }
+
+    #[tokio::test]
+    async fn test_get_public_ip() {
+        let detector =
+            IpDetector::new(&IpDetectionConfig::default(), &RetryConfig::default()).unwrap();
+        let result = detector.get_public_ip(IpVersion::V4).await;
+
+        // This test depends on network connectivity, so we'll just check
+        // that it either succeeds or fails gracefully
+        match result {
+            Ok(IpAddr::V4(ip)) => {
+                println!("Detected IP: {}", ip);
+                assert!(!ip.is_loopback());
+                assert!(!ip.is_private());
+            }
+            Ok(ip) => panic!("Expected an IPv4 address, got {ip}"),
+            Err(e) => {
+                println!("IP detection failed (this is OK in CI): {}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_checks_family() {
+        assert_eq!(
+            parse_ip("81.2.69.160", IpVersion::V4).unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_ip("2a00:1450:4001:82b::200e", IpVersion::V6).unwrap(),
+            "2a00:1450:4001:82b::200e".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_ip("81.2.69.160", IpVersion::V6).is_err());
+        assert!(parse_ip("2a00:1450:4001:82b::200e", IpVersion::V4).is_err());
+        assert!(parse_ip("<html>", IpVersion::V4).is_err());
+    }
+
+    #[test]
+    fn test_extract_formats() {
+        let text = ResponseParser::Text;
+        assert_eq!(text.extract(" 81.2.69.160\n").unwrap(), "81.2.69.160");
+
+        let json = HttpService::from_config(&http(
+            "http://localhost/",
+            ResponseFormat::Json {
+                field: "data.addresses.0".to_string(),
+            },
+        ))
+        .unwrap()
+        .parser;
+        assert_eq!(
+            json.extract(r#"{"data": {"addresses": ["81.2.69.160"]}}"#)
+                .unwrap(),
+            "81.2.69.160"
+        );
+        assert!(json.extract(r#"{"data": {}}"#).is_err());
+        assert!(json.extract(r#"{"data": {"addresses": [42]}}"#).is_err());
+        assert!(json.extract("not json").is_err());
+
+        let regex = ResponseParser::Regex(Regex::new(r"Address: ([0-9.]+)").unwrap());
+        assert_eq!(
+            regex
+                .extract("<body>Current IP Address: 81.2.69.160</body>")
+                .unwrap(),
+            "81.2.69.160"
+        );
+        assert!(regex.extract("<body>nothing here</body>").is_err());
+
+        let whole_match = ResponseParser::Regex(Regex::new(r"\d+\.\d+\.\d+\.\d+").unwrap());
+        assert_eq!(
+            whole_match.extract("ip=81.2.69.160;").unwrap(),
+            "81.2.69.160"
+        );
+    }
+
+    #[test]
+    fn test_invalid_service_config() {
+        let bad_regex = http(
+            "http://localhost/",
+            ResponseFormat::Regex {
+                pattern: "(".to_string(),
+            },
+        );
+        assert!(HttpService::from_config(&bad_regex).is_err());
+
+        let mut bad_header = http("http://localhost/", ResponseFormat::Text);
+        bad_header
+            .headers
+            .insert("Bad Header".to_string(), "x".to_string());
+        assert!(HttpService::from_config(&bad_header).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_services_tried_in_order_with_headers() {
+        let broken = HttpStub::start(500, "oops").await;
+        let good = HttpStub::start(200, r#"{"ip": "81.2.69.160"}"#).await;
+
+        let mut json = http(
+            &good.url(),
+            ResponseFormat::Json {
+                field: "ip".to_string(),
+            },
+        );
+        json.headers
+            .insert("X-Api-Key".to_string(), "secret".to_string());
+
+        let detector = detector(vec![
+            service(&broken.url(), ResponseFormat::Text),
+            IpServiceConfig::Http(json),
+        ]);
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+        assert_eq!(broken.requests().len(), 1);
+        let requests = good.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].to_lowercase().contains("x-api-key: secret"));
+    }
+
+    #[tokio::test]
+    async fn test_service_timeout() {
+        let slow = HttpStub::start_with_delay(200, "81.2.69.160", Duration::from_secs(5)).await;
+        let mut config = http(&slow.url(), ResponseFormat::Text);
+        config.timeout = 1;
+
+        let outside = detector(vec![IpServiceConfig::Http(config)]);
+        assert!(outside.get_public_ip(IpVersion::V4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_rounds_retried() {
+        let retry = RetryConfig {
+            max_attempts: 3,
+            ..NO_RETRY
+        };
+
+        // A server error may go away, so the round is repeated
+        let overloaded = HttpStub::start(503, "unavailable").await;
+        let mut detector = detector(vec![service(&overloaded.url(), ResponseFormat::Text)]);
+        detector.retry = retry;
+        let err = detector.get_public_ip(IpVersion::V4).await.unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(overloaded.requests().len(), 3);
+
+        // A missing endpoint will not
+        let missing = HttpStub::start(404, "not found").await;
+        let mut detector =
+            consensus_detector(vec![service(&missing.url(), ResponseFormat::Text)], 1, 1);
+        detector.retry = retry;
+        let err = detector.get_public_ip(IpVersion::V4).await.unwrap_err();
+        assert!(!err.is_retryable());
+        assert_eq!(missing.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_consensus_reports_dissenters() {
+        let (stubs, services) =
+            text_stubs(&["81.2.69.160", "6.6.6.6", "81.2.69.160", "81.2.69.161"]).await;
+        let detector = consensus_detector(services, 3, 2);
+
+        let outcome = detector.get_consensus(IpVersion::V4).await.unwrap();
+        assert_eq!(outcome.address, "81.2.69.160".parse::<IpAddr>().unwrap());
+        assert_eq!(outcome.agreeing, vec![stubs[0].url(), stubs[2].url()]);
+        assert_eq!(
+            outcome.dissenters,
+            vec![(stubs[1].url(), "6.6.6.6".to_string())]
+        );
+        // Only the first `queries` services are asked
+        assert!(stubs[3].requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_consensus_requires_min_agree() {
+        let (_stubs, mut services) = text_stubs(&["81.2.69.160", "6.6.6.6"]).await;
+        let broken = HttpStub::start(503, "unavailable").await;
+        services.push(service(&broken.url(), ResponseFormat::Text));
+        let detector = consensus_detector(services, 3, 2);
+
+        let err = detector.get_public_ip(IpVersion::V4).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No IPv4 address reported by at least 2 of 3 services"));
+    }
+
+    #[tokio::test]
+    async fn test_consensus_rejects_ties() {
+        let (_stubs, services) =
+            text_stubs(&["81.2.69.160", "6.6.6.6", "81.2.69.160", "6.6.6.6"]).await;
+        let detector = consensus_detector(services, 4, 2);
+
+        let err = detector.get_public_ip(IpVersion::V4).await.unwrap_err();
+        assert!(err.to_string().contains("Conflicting IPv4 addresses"));
+    }
+
+    #[test]
+    fn test_non_public_ranges() {
+        for (ip, range) in [
+            ("0.0.0.0", "unspecified"),
+            ("127.0.0.1", "loopback"),
+            ("10.1.2.3", "private"),
+            ("172.16.0.1", "private"),
+            ("192.168.1.20", "private"),
+            ("100.64.0.1", "CGNAT"),
+            ("100.127.255.254", "CGNAT"),
+            ("169.254.10.1", "link-local"),
+            ("192.0.2.1", "documentation"),
+            ("203.0.113.10", "documentation"),
+            ("224.0.0.251", "multicast"),
+            ("198.18.0.1", "reserved"),
+            ("255.255.255.255", "reserved"),
+            ("::", "unspecified"),
+            ("::1", "loopback"),
+            ("fd12:3456::1", "private"),
+            ("fe80::1", "link-local"),
+            ("2001:db8::10", "documentation"),
+            ("ff02::1", "multicast"),
+            ("::ffff:81.2.69.160", "reserved"),
+        ] {
+            assert_eq!(
+                non_public_range(&ip.parse().unwrap()),
+                Some(range),
+                "{ip} should be {range}"
+            );
+        }
+
+        for ip in [
+            "81.2.69.160",
+            "100.128.0.1",
+            "6.6.6.6",
+            "2a00:1450:4001:82b::200e",
+        ] {
+            assert_eq!(non_public_range(&ip.parse().unwrap()), None, "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_public_answer_tries_next_service() {
+        let (stubs, services) = text_stubs(&["100.64.12.34", "81.2.69.160"]).await;
+        let detector = detector(services);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+        assert_eq!(stubs[0].requests().len(), 1);
+        assert_eq!(stubs[1].requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_non_public_answer_allowlisted() {
+        let captive = HttpStub::start(200, "10.20.30.40").await;
+        let mut config = http(&captive.url(), ResponseFormat::Text);
+        config.allow = vec!["10.20.0.0/16".parse().unwrap()];
+
+        let allowed = detector(vec![IpServiceConfig::Http(config)]);
+        assert_eq!(
+            allowed.get_public_ip(IpVersion::V4).await.unwrap(),
+            "10.20.30.40".parse::<IpAddr>().unwrap()
+        );
+
+        let other = HttpStub::start(200, "10.99.0.1").await;
+        let mut config = http(&other.url(), ResponseFormat::Text);
+        config.allow = vec!["10.20.0.0/16".parse().unwrap()];
+
+        let outside = detector(vec![IpServiceConfig::Http(config)]);
+        assert!(outside.get_public_ip(IpVersion::V4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_consensus_ignores_non_public_answers() {
+        let (stubs, services) = text_stubs(&["192.168.1.1", "192.168.1.1", "81.2.69.160"]).await;
+        let detector = consensus_detector(services, 3, 2);
+
+        let err = detector.get_consensus(IpVersion::V4).await.unwrap_err();
+        assert!(err.to_string().contains("No IPv4 address reported"));
+        assert!(format!("{err:#}").contains(&format!(
+            "Rejected private address 192.168.1.1 from {}",
+            stubs[0].url()
+        )));
+    }
+
+    fn interface(
+        name: &str,
+        scope: AddressScope,
+        prefix: Option<&str>,
+        exclude_private: bool,
+    ) -> InterfaceSource {
+        InterfaceSource::new(&InterfaceSourceConfig {
+            interface: name.to_string(),
+            scope,
+            prefix: prefix.map(|p| p.parse().unwrap()),
+            exclude_private,
+        })
+    }
+
+    #[test]
+    fn test_interface_address_filters() {
+        let addresses: Vec<IpAddr> = [
+            "127.0.0.1",
+            "169.254.10.1",
+            "192.168.1.20",
+            "100.72.0.5",
+            "81.2.69.160",
+            "fe80::1",
+            "fd12:3456::1",
+            "2001:db8:1::1",
+            "2a00:1450:4001:82b::200e",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+
+        let defaults = interface("eth0", AddressScope::Global, None, true);
+        assert_eq!(
+            defaults.select(&addresses, IpVersion::V4).unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            defaults.select(&addresses, IpVersion::V6).unwrap(),
+            "2001:db8:1::1".parse::<IpAddr>().unwrap()
+        );
+
+        let with_private = interface("eth0", AddressScope::Global, None, false);
+        assert_eq!(
+            with_private.select(&addresses, IpVersion::V4).unwrap(),
+            "192.168.1.20".parse::<IpAddr>().unwrap()
+        );
+
+        let any_scope = interface("eth0", AddressScope::Any, None, false);
+        assert_eq!(
+            any_scope.select(&addresses, IpVersion::V6).unwrap(),
+            "fe80::1".parse::<IpAddr>().unwrap()
+        );
+
+        let prefixed = interface("eth0", AddressScope::Global, Some("2a00:1450::/32"), true);
+        assert_eq!(
+            prefixed.select(&addresses, IpVersion::V6).unwrap(),
+            "2a00:1450:4001:82b::200e".parse::<IpAddr>().unwrap()
+        );
+        assert!(prefixed.select(&addresses, IpVersion::V4).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interface_lookup() {
+        let loopback = interface("lo", AddressScope::Any, None, false);
+        // Not every test environment has an interface called "lo"
+        if let Ok(ip) = loopback.get_ip(IpVersion::V4).await {
+            assert!(ip.is_loopback());
+        }
+
+        let missing = interface("does-not-exist0", AddressScope::Global, None, true);
+        assert!(missing.get_ip(IpVersion::V4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_static_source() {
+        let source = StaticSource::new(&StaticSourceConfig {
+            addresses: vec!["81.2.69.160".parse().unwrap()],
+        });
+
+        assert_eq!(
+            source.get_ip(IpVersion::V4).await.unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert!(source.get_ip(IpVersion::V6).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_source() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "2a00:1450:4001:82b::200e\n81.2.69.160").unwrap();
+        let source = FileSource::new(&FileSourceConfig {
+            path: file.path().to_path_buf(),
+        });
+
+        assert_eq!(
+            source.get_ip(IpVersion::V4).await.unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            source.get_ip(IpVersion::V6).await.unwrap(),
+            "2a00:1450:4001:82b::200e".parse::<IpAddr>().unwrap()
+        );
+
+        let missing = FileSource::new(&FileSourceConfig {
+            path: PathBuf::from("/nonexistent/wan-ip"),
+        });
+        assert!(missing.get_ip(IpVersion::V4).await.is_err());
+    }
+
+    fn command(command: &str, args: &[&str], timeout: u64) -> CommandSource {
+        CommandSource::new(&CommandSourceConfig {
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_command_source() {
+        let echo = command("echo", &["81.2.69.160"], 5);
+        assert_eq!(
+            echo.get_ip(IpVersion::V4).await.unwrap(),
+            "81.2.69.160".parse::<IpAddr>().unwrap()
+        );
+        assert!(echo.get_ip(IpVersion::V6).await.is_err());
+
+        let failing = command("sh", &["-c", "echo 81.2.69.160; exit 1"], 5);
+        assert!(failing.get_ip(IpVersion::V4).await.is_err());
+
+        let slow = command("sleep", &["5"], 1);
+        let err = anyhow::Error::new(slow.get_ip(IpVersion::V4).await.unwrap_err());
+        assert!(format!("{err:#}").contains("timed out"));
+    }
+
+    fn stun(server: &str, timeout: u64) -> IpServiceConfig {
+        IpServiceConfig::Stun(StunServiceConfig {
+            server: server.to_string(),
+            timeout,
+            allow: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_parse_stun_response() {
+        let transaction_id = [7u8; 12];
+        let v4: IpAddr = "81.2.69.160".parse().unwrap();
+        let v6: IpAddr = "2a00:1450:4001:82b::200e".parse().unwrap();
+
+        let xor = stun_response(&transaction_id, v4, true);
+        assert_eq!(parse_stun_response(&xor, &transaction_id).unwrap(), v4);
+        // The XOR encoding must actually differ from the plain address
+        assert!(!xor.windows(4).any(|w| w == [81, 2, 69, 160]));
+
+        let plain = stun_response(&transaction_id, v4, false);
+        assert_eq!(parse_stun_response(&plain, &transaction_id).unwrap(), v4);
+
+        let xor_v6 = stun_response(&transaction_id, v6, true);
+        assert_eq!(parse_stun_response(&xor_v6, &transaction_id).unwrap(), v6);
+
+        // Responses to someone else's request are rejected
+        assert!(parse_stun_response(&xor, &[8u8; 12]).is_err());
+        assert!(parse_stun_response(&xor[..10], &transaction_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stun_service() {
+        let responder = UdpStub::stun("81.2.69.160".parse().unwrap()).await;
+        let detector = detector(vec![stun(&responder.addr().to_string(), 2)]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+        assert_eq!(responder.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stun_falls_back_to_http() {
+        // Nothing listens here, so the STUN request times out
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let http_stub = HttpStub::start(200, "81.2.69.161").await;
+        let detector = detector(vec![
+            stun(&silent.local_addr().unwrap().to_string(), 1),
+            service(&http_stub.url(), ResponseFormat::Text),
+        ]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.161".parse::<IpAddr>().unwrap());
+    }
+
+    fn dns(resolver: &str, query: &str, record_type: Option<DnsQueryType>) -> IpServiceConfig {
+        IpServiceConfig::Dns(DnsServiceConfig {
+            resolver: resolver.to_string(),
+            query: query.to_string(),
+            record_type,
+            timeout: 2,
+            allow: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_parse_dns_response() {
+        let query = dns_query(0x1234, "myip.opendns.com", DNS_TYPE_A).unwrap();
+        assert_eq!(&query[12..17], b"\x04myip");
+
+        let a = dns_response(&query, DNS_TYPE_A, &[&[81, 2, 69, 160]], 0);
+        assert_eq!(
+            parse_dns_response(&a, 0x1234, DNS_TYPE_A).unwrap(),
+            ["81.2.69.160"]
+        );
+
+        let txt = dns_response(&query, DNS_TYPE_TXT, &[b"\x0b81.2.69.160"], 0);
+        assert_eq!(
+            parse_dns_response(&txt, 0x1234, DNS_TYPE_TXT).unwrap(),
+            ["81.2.69.160"]
+        );
+
+        let nxdomain = dns_response(&query, DNS_TYPE_A, &[], 3);
+        let err = parse_dns_response(&nxdomain, 0x1234, DNS_TYPE_A).unwrap_err();
+        assert!(err.to_string().contains("error code 3"));
+
+        assert!(parse_dns_response(&a, 0x4321, DNS_TYPE_A).is_err());
+        assert!(parse_dns_response(&a[..a.len() - 2], 0x1234, DNS_TYPE_A).is_err());
+        assert!(dns_query(1, "bad..name", DNS_TYPE_A).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_service() {
+        let resolver = UdpStub::dns(DNS_TYPE_A, vec![vec![81, 2, 69, 160]]).await;
+        let detector = detector(vec![dns(
+            &resolver.addr().to_string(),
+            "myip.opendns.com",
+            None,
+        )]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+        let queries: Vec<_> = resolver
+            .requests()
+            .iter()
+            .map(|query| question_name(query))
+            .collect();
+        assert_eq!(queries, ["myip.opendns.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_dns_service_txt() {
+        let resolver = UdpStub::dns(DNS_TYPE_TXT, vec![b"\x0b81.2.69.160".to_vec()]).await;
+        let detector = detector(vec![dns(
+            &resolver.addr().to_string(),
+            "o-o.myaddr.l.google.com",
+            Some(DnsQueryType::Txt),
+        )]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+    }
+
+    const IGD_DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+    </serviceList>
+    <deviceList>
+      <device>
+        <serviceList>
+          <service>
+            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+            <controlURL>/ctl/IPConn</controlURL>
+          </service>
+        </serviceList>
+      </device>
+    </deviceList>
+  </device>
+</root>"#;
+
+    const SOAP_RESPONSE: &str = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>
+<u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewExternalIPAddress>81.2.69.160</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse></s:Body></s:Envelope>"#;
+
+    #[test]
+    fn test_parse_igd_description() {
+        let (control_url, service_type) =
+            parse_igd_description(IGD_DESCRIPTION, "http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(control_url.as_str(), "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(
+            service_type,
+            "urn:schemas-upnp-org:service:WANIPConnection:1"
+        );
+
+        let with_base = IGD_DESCRIPTION.replace(
+            "<device>",
+            "<URLBase>http://192.168.1.1:49000/</URLBase><device>",
+        );
+        let (control_url, _) =
+            parse_igd_description(&with_base, "http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(control_url.as_str(), "http://192.168.1.1:49000/ctl/IPConn");
+
+        let ppp = IGD_DESCRIPTION.replace("WANIPConnection", "WANPPPConnection");
+        assert!(parse_igd_description(&ppp, "http://192.168.1.1/").is_ok());
+
+        let not_a_gateway = IGD_DESCRIPTION.replace("WANIPConnection", "ContentDirectory");
+        assert!(parse_igd_description(&not_a_gateway, "http://192.168.1.1/").is_err());
+
+        assert_eq!(
+            xml_element(SOAP_RESPONSE, "NewExternalIPAddress").as_deref(),
+            Some("81.2.69.160")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upnp_service() {
+        let gateway = HttpStub::start_routes(&[
+            ("/rootDesc.xml", 200, IGD_DESCRIPTION),
+            ("/ctl/IPConn", 200, SOAP_RESPONSE),
+        ])
+        .await;
+        let detector = detector(vec![IpServiceConfig::Upnp(UpnpServiceConfig {
+            location: Some(format!("{}rootDesc.xml", gateway.url())),
+            timeout: 2,
+            allow: Vec::new(),
+        })]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+
+        let requests = gateway.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("POST /ctl/IPConn "));
+        assert!(requests[1].to_lowercase().contains(
+            r#"soapaction: "urn:schemas-upnp-org:service:wanipconnection:1#getexternalipaddress""#
+        ));
+        assert!(requests[1].contains("<u:GetExternalIPAddress"));
+    }
+
+    #[tokio::test]
+    async fn test_discover_igd() {
+        let location = "http://192.168.1.1:5000/rootDesc.xml";
+        let responder = UdpStub::start(move |request| {
+            let request = String::from_utf8_lossy(request);
+            request.contains(IGD_DEVICE_TYPE).then(|| {
+                format!("HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: {location}\r\nST: {IGD_DEVICE_TYPE}\r\n\r\n")
+                    .into_bytes()
+            })
+        })
+        .await;
+
+        let found = discover_igd(responder.addr(), Duration::from_secs(2))
+            .await
+            .unwrap();
+        assert_eq!(found, location);
+
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = discover_igd(silent.local_addr().unwrap(), Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No UPnP gateway answered"));
+    }
+
+    #[test]
+    fn test_default_gateway() {
+        let header =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n";
+        let lan = "eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        let default = "eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+
+        if cfg!(target_endian = "little") {
+            assert_eq!(
+                default_gateway(&format!("{header}{lan}{default}")),
+                Some(Ipv4Addr::new(192, 168, 1, 1))
+            );
+        }
+        assert_eq!(default_gateway(&format!("{header}{lan}")), None);
+    }
+
+    fn gateway(addr: SocketAddr) -> GatewayServiceConfig {
+        GatewayServiceConfig {
+            gateway: Some(addr.to_string()),
+            timeout: 2,
+            allow: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp_service() {
+        let responder = UdpStub::start(|request| {
+            (request == NAT_PMP_EXTERNAL_ADDRESS_REQUEST)
+                .then(|| vec![0, 128, 0, 0, 0, 0, 0x1c, 0x20, 81, 2, 69, 160])
+        })
+        .await;
+        let detector = detector(vec![IpServiceConfig::NatPmp(gateway(responder.addr()))]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+
+        let refused = [0, 128, 0, 3, 0, 0, 0x1c, 0x20, 0, 0, 0, 0];
+        let err = parse_nat_pmp_response(&refused).unwrap_err();
+        assert!(err.to_string().contains("result code 3"));
+        assert!(parse_nat_pmp_response(&refused[..8]).is_err());
+    }
+
+    fn pcp_map_response(request: &[u8], result: u8, external: Ipv6Addr) -> Vec<u8> {
+        let mut response = vec![PCP_VERSION, PCP_RESPONSE_BIT | PCP_OPCODE_MAP, 0, result];
+        response.extend_from_slice(&request[4..8]); // granted lifetime
+        response.extend_from_slice(&[0, 0, 0, 42]); // epoch
+        response.extend_from_slice(&[0; 12]);
+        response.extend_from_slice(&request[24..42]); // nonce, protocol, internal port
+        response.extend_from_slice(&40000u16.to_be_bytes());
+        response.extend_from_slice(&external.octets());
+        response
+    }
+
+    #[tokio::test]
+    async fn test_pcp_service() {
+        let external = Ipv4Addr::new(81, 2, 69, 160).to_ipv6_mapped();
+        let responder = UdpStub::start(move |request| {
+            (request.len() == 60 && request[1] == PCP_OPCODE_MAP)
+                .then(|| pcp_map_response(request, 0, external))
+        })
+        .await;
+        let detector = detector(vec![IpServiceConfig::Pcp(gateway(responder.addr()))]);
+
+        let ip = detector.get_public_ip(IpVersion::V4).await.unwrap();
+        assert_eq!(ip, "81.2.69.160".parse::<IpAddr>().unwrap());
+
+        // The mapping is released again with a zero lifetime
+        for _ in 0..20 {
+            if responder.requests().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let requests = responder.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0][4..8], PCP_MAPPING_LIFETIME.to_be_bytes());
+        assert_eq!(
+            requests[0][8..24],
+            Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets()
+        );
+        assert_eq!(requests[1][4..8], [0, 0, 0, 0]);
+        assert_eq!(requests[0][24..36], requests[1][24..36]);
+    }
+
+    #[test]
+    fn test_parse_pcp_map_response() {
+        let nonce = [9u8; 12];
+        let request = pcp_map_request(&nonce, "192.168.1.20:40000".parse().unwrap(), 60);
+        assert_eq!(request.len(), 60);
+
+        let v6: Ipv6Addr = "2a00:1450:4001:82b::200e".parse().unwrap();
+        let response = pcp_map_response(&request, 0, v6);
+        assert_eq!(
+            parse_pcp_map_response(&response, &nonce).unwrap(),
+            IpAddr::V6(v6)
+        );
+        assert!(parse_pcp_map_response(&response, &[1u8; 12]).is_err());
+
+        let not_authorized = pcp_map_response(&request, 2, Ipv6Addr::UNSPECIFIED);
+        let err = parse_pcp_map_response(&not_authorized, &nonce).unwrap_err();
+        assert!(err.to_string().contains("result code 2"));
     }
 }
@@ -1,16 +1,37 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use clap::Parser;
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::future::Future;
 use std::io::{self, Write};
-use std::time::Duration;
-use tracing::{error, info, warn};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 mod config;
 mod dns;
 mod ip;
+mod netlink;
+mod retry;
+mod schedule;
+mod signals;
+mod state;
+#[cfg(test)]
+mod test_util;
 
-use config::Config;
-use dns::{DnsOperations, DnsUpdater, MockDnsUpdater};
-use ip::IpDetector;
+use config::{AssumeRole, Config, DnsRecord, HumanDuration, IpSourceConfig};
+use dns::{
+    DnsError, DnsOperations, DnsUpdater, MockDnsUpdater, RecordChange, MAX_CHANGES_PER_BATCH,
+};
+use ip::{IpSource, IpVersion, MockIpSource};
+use netlink::NetworkChanges;
+use schedule::Schedule;
+use signals::{Signal, Signals};
+use state::{RecordSettings, State};
 
 #[derive(Parser)]
 #[command(name = "auto-dns")]
@@ -31,18 +52,23 @@ struct Cli {
     /// Simulate AWS operations without making actual API calls (dry run mode)
     #[arg(long)]
     no_aws: bool,
+
+    /// Wait for each change to reach all Route53 name servers before continuing
+    #[arg(long)]
+    wait_for_sync: bool,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
 
     // Validate that write-config is used alone
     if let Some(config_path) = &cli.write_config {
-        if cli.once || cli.config != "config.toml" || cli.no_aws {
+        if cli.once || cli.config != "config.toml" || cli.no_aws || cli.wait_for_sync {
             bail!("--write-config cannot be used with other flags");
         }
-        return create_config_interactively(config_path).await;
+        create_config_interactively(config_path).await?;
+        return Ok(ExitCode::SUCCESS);
     }
 
     // Initialize logging
@@ -52,33 +78,128 @@ async fn main() -> Result<()> {
 
     info!("Starting auto-dns updater");
 
-    // Load configuration
-    let config = Config::load(&cli.config).await?;
-    info!("Loaded configuration for {} records", config.records.len());
-
-    // Initialize components
-    let ip_detector = IpDetector::new();
-
+    let source = ConfigSource {
+        path: cli.config.clone(),
+        wait_for_sync: cli.wait_for_sync,
+        dry_run: cli.no_aws,
+    };
     if cli.no_aws {
         info!("Running in dry-run mode (--no-aws). No actual AWS API calls will be made.");
-        let mock_dns_updater = MockDnsUpdater::new();
+    }
+    let mut setup = Setup::load(source).await?;
 
-        if cli.once {
-            run_update(&ip_detector, &mock_dns_updater, &config).await?;
-        } else {
-            run_continuous(&ip_detector, &mock_dns_updater, &config).await?;
+    let mut state = load_state(&setup.config, cli.no_aws).await;
+
+    if cli.once {
+        let result = run_update(&setup.ip_sources, &setup.dns(), &setup.config, &mut state).await?;
+        result.log();
+        save_state(&state).await;
+        return Ok(result.exit_code());
+    }
+    let mut signals = Signals::listen().context("Failed to set up signal handling")?;
+    signals.watch_config(Path::new(&cli.config));
+    run_continuous(&mut setup, &mut state, &mut signals).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Where the config comes from: the `--config` file, with the command line's overrides.
+#[derive(Clone)]
+struct ConfigSource {
+    path: String,
+    wait_for_sync: bool,
+    dry_run: bool,
+}
+
+/// The config and the clients built from it, replaced as a whole when the config
+/// file is reloaded.
+struct Setup {
+    source: ConfigSource,
+    config: Config,
+    ip_sources: IpSources,
+    /// Route53 with the default credentials, and with each role records assume
+    dns: Box<dyn DnsOperations>,
+    roles: Vec<(AssumeRole, Box<dyn DnsOperations>)>,
+}
+
+impl Setup {
+    async fn load(source: ConfigSource) -> Result<Self> {
+        let mut config = Config::load(&source.path).await?;
+        if source.wait_for_sync {
+            config.propagation.wait_for_sync = true;
         }
-    } else {
-        let dns_updater = DnsUpdater::new(&config.aws).await?;
+        info!("Loaded configuration for {} records", config.records.len());
 
-        if cli.once {
-            run_update(&ip_detector, &dns_updater, &config).await?;
-        } else {
-            run_continuous(&ip_detector, &dns_updater, &config).await?;
+        if source.dry_run {
+            return Ok(Self {
+                source,
+                ip_sources: IpSources::new(Box::new(MockIpSource::new()), &config, true),
+                config,
+                dns: Box::new(MockDnsUpdater::new()),
+                roles: Vec::new(),
+            });
+        }
+
+        let ip_source =
+            ip::source_from_config(&config.ip_source, &config.ip_detection, &config.retry)?;
+        let ip_sources = IpSources::new(ip_source, &config, false);
+        let dns = Box::new(DnsUpdater::new(&config.aws, None).await?);
+        // One client per role, each refreshing its own assumed credentials
+        let roles: BTreeSet<AssumeRole> = config
+            .records
+            .iter()
+            .filter_map(|record| config.role_for(record))
+            .collect();
+        let mut role_updaters: Vec<(AssumeRole, Box<dyn DnsOperations>)> = Vec::new();
+        for role in roles {
+            info!("Assuming role {} for its records", role.role_arn);
+            let updater = DnsUpdater::new(&config.aws, Some(&role)).await?;
+            role_updaters.push((role, Box::new(updater)));
         }
+        Ok(Self {
+            source,
+            config,
+            ip_sources,
+            dns,
+            roles: role_updaters,
+        })
     }
 
-    Ok(())
+    /// Loads the config file again and returns the config it replaces. If the file is
+    /// invalid or its clients cannot be set up, this setup stays as it was.
+    async fn reload(&mut self) -> Result<Config> {
+        let new = Self::load(self.source.clone()).await?;
+        Ok(std::mem::replace(self, new).config)
+    }
+
+    fn dns(&self) -> DnsAccounts<'_> {
+        DnsAccounts {
+            default: self.dns.as_ref(),
+            roles: self
+                .roles
+                .iter()
+                .map(|(role, updater)| (role.clone(), updater.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+/// The state file's contents, or an empty state without one and in dry runs.
+async fn load_state(config: &Config, dry_run: bool) -> State {
+    match &config.state.path {
+        Some(_) if dry_run => {
+            info!("Not using the state file in dry-run mode");
+            State::default()
+        }
+        Some(path) => State::load(path).await,
+        None => State::default(),
+    }
+}
+
+/// Saves `state` after a cycle. Failing to only costs lookups the state would have saved.
+async fn save_state(state: &State) {
+    if let Err(e) = state.save().await {
+        warn!("{:#}", anyhow::Error::new(e));
+    }
 }
 
 async fn create_config_interactively(config_path: &str) -> Result<()> {
@@ -87,7 +208,7 @@ async fn create_config_interactively(config_path: &str) -> Result<()> {
 
     // Get AWS configuration
     println!("AWS Configuration:");
-    print!("AWS Region (e.g., us-east-1): ");
+    print!("AWS Region (optional, e.g. us-east-1, leave empty to use the default): ");
     io::stdout().flush()?;
     let mut region = String::new();
     io::stdin().read_line(&mut region)?;
@@ -117,7 +238,7 @@ async fn create_config_interactively(config_path: &str) -> Result<()> {
     for i in 1..=num_records {
         println!("\nRecord {i}:");
 
-        print!("Hosted Zone ID: ");
+        print!("Hosted Zone ID (optional, leave empty to look it up by record name): ");
         io::stdout().flush()?;
         let mut hosted_zone_id = String::new();
         io::stdin().read_line(&mut hosted_zone_id)?;
@@ -139,19 +260,37 @@ async fn create_config_interactively(config_path: &str) -> Result<()> {
             ttl.trim().parse()?
         };
 
+        print!("Record type (A, AAAA or BOTH, default A): ");
+        io::stdout().flush()?;
+        let mut record_type = String::new();
+        io::stdin().read_line(&mut record_type)?;
+        let record_type = match record_type.trim().to_uppercase().as_str() {
+            "" | "A" => "A",
+            "AAAA" => "AAAA",
+            "BOTH" => "BOTH",
+            other => bail!("Invalid record type: {other} (expected A, AAAA or BOTH)"),
+        };
+
+        let hosted_zone_id = if hosted_zone_id.is_empty() {
+            String::new()
+        } else {
+            format!("\nhosted_zone_id = \"{hosted_zone_id}\"")
+        };
         records.push(format!(
             r#"
-[[records]]
-hosted_zone_id = "{hosted_zone_id}"
+[[records]]{hosted_zone_id}
 name = "{name}"
-ttl = {ttl}"#,
+ttl = {ttl}
+record_type = "{record_type}""#,
         ));
     }
 
     // Generate config content
     let mut config_content = String::new();
     config_content.push_str("[aws]\n");
-    config_content.push_str(&format!("region = \"{region}\"\n"));
+    if !region.is_empty() {
+        config_content.push_str(&format!("region = \"{region}\"\n"));
+    }
 
     if !access_key_id.is_empty() && !secret_access_key.is_empty() {
         config_content.push_str(&format!("access_key_id = \"{access_key_id}\"\n"));
@@ -169,85 +308,1803 @@ ttl = {ttl}"#,
     Ok(())
 }
 
+/// What an update cycle did with one record (one address family of a configured record).
+#[derive(Debug)]
+enum Outcome {
+    /// Route53 already had the current address; `checked` is false when that was
+    /// known from the state file and Route53 was not asked
+    Unchanged {
+        checked: bool,
+    },
+    Updated {
+        previous: IpAddr,
+    },
+    Created,
+    /// An earlier entry for the same record and family was used instead
+    Duplicate,
+    /// Nothing was changed; the error says why
+    Failed(Arc<anyhow::Error>),
+}
+
+#[derive(Debug)]
+struct RecordOutcome {
+    name: String,
+    version: IpVersion,
+    settings: RecordSettings,
+    /// The address the record should have, if it could be detected
+    ip: Option<IpAddr>,
+    outcome: Outcome,
+    /// How long the change took to reach all Route53 name servers, if we waited for it
+    in_sync_after: Option<Duration>,
+}
+
+impl RecordOutcome {
+    fn new(
+        config: &Config,
+        record: &DnsRecord,
+        version: IpVersion,
+        ip: Option<IpAddr>,
+        outcome: Outcome,
+    ) -> Self {
+        Self {
+            name: record.name.clone(),
+            version,
+            settings: record_settings(config, record),
+            ip,
+            outcome,
+            in_sync_after: None,
+        }
+    }
+
+    fn failed(&self) -> Option<&anyhow::Error> {
+        match &self.outcome {
+            Outcome::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What the state file remembers of `record`'s configuration.
+fn record_settings(config: &Config, record: &DnsRecord) -> RecordSettings {
+    RecordSettings {
+        hosted_zone_id: record.hosted_zone_id.clone(),
+        role_arn: config.role_for(record).map(|role| role.role_arn),
+        ttl: record.ttl,
+    }
+}
+
+/// A configured record by name, zone and role, which tell apart entries for the same
+/// name in several zones or accounts.
+type EntryKey = (String, Option<String>, Option<String>);
+
+fn entry_key(name: &str, settings: &RecordSettings) -> EntryKey {
+    (
+        name.to_string(),
+        settings.hosted_zone_id.clone(),
+        settings.role_arn.clone(),
+    )
+}
+
+/// What an update cycle did, record by record in configuration order.
+#[derive(Debug, Default)]
+struct CycleResult {
+    records: Vec<RecordOutcome>,
+}
+
+impl CycleResult {
+    fn count(&self, matches: impl Fn(&Outcome) -> bool) -> usize {
+        self.records.iter().filter(|r| matches(&r.outcome)).count()
+    }
+
+    fn failures(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Failed(_)))
+    }
+
+    /// The records that failed because their credentials cannot be used, with the error.
+    fn auth_failures(&self) -> impl Iterator<Item = (&RecordOutcome, &anyhow::Error)> {
+        self.records.iter().filter_map(|record| {
+            let e = record.failed()?;
+            e.downcast_ref::<DnsError>()
+                .is_some_and(DnsError::is_auth_failure)
+                .then_some((record, e))
+        })
+    }
+
+    /// 0 if every record is up to date, 1 if every record failed and 2 if only some did.
+    fn exit_code(&self) -> ExitCode {
+        match self.failures() {
+            0 => ExitCode::SUCCESS,
+            n if n == self.records.len() => ExitCode::FAILURE,
+            _ => ExitCode::from(2),
+        }
+    }
+
+    /// Records what the cycle learned about each record's address in `state`.
+    fn remember(&self, state: &mut State, now: DateTime<Utc>) {
+        for record in &self.records {
+            let Some(ip) = record.ip else {
+                continue;
+            };
+            match record.outcome {
+                Outcome::Unchanged { checked: false } | Outcome::Duplicate => {}
+                Outcome::Unchanged { checked: true }
+                | Outcome::Updated { .. }
+                | Outcome::Created => state.published(
+                    &record.name,
+                    record.version,
+                    ip,
+                    record.settings.clone(),
+                    now,
+                ),
+                Outcome::Failed(_) => {
+                    state.detected(&record.name, record.version, ip, &record.settings)
+                }
+            }
+        }
+    }
+
+    fn log(&self) {
+        let unchanged = self.count(|o| matches!(o, Outcome::Unchanged { .. }));
+        if unchanged == self.records.len() {
+            info!("Update cycle finished: all records up to date");
+            return;
+        }
+
+        let mut summary = format!(
+            "Update cycle finished: {} unchanged, {} updated, {} created, {} failed",
+            unchanged,
+            self.count(|o| matches!(o, Outcome::Updated { .. })),
+            self.count(|o| matches!(o, Outcome::Created)),
+            self.failures()
+        );
+        let duplicates = self.count(|o| matches!(o, Outcome::Duplicate));
+        if duplicates > 0 {
+            summary.push_str(&format!(", {duplicates} duplicate(s) skipped"));
+        }
+        if self.failures() > 0 {
+            warn!("{}", summary);
+        } else {
+            info!("{}", summary);
+        }
+
+        for record in &self.records {
+            let ip = record.ip.map(|ip| ip.to_string()).unwrap_or_default();
+            let synced = match record.in_sync_after {
+                Some(took) => format!(" (in sync after {:.1}s)", took.as_secs_f64()),
+                None => String::new(),
+            };
+            match &record.outcome {
+                Outcome::Unchanged { .. } => {}
+                Outcome::Updated { previous } => info!(
+                    "  {} {}: updated {} -> {}{}",
+                    record.name,
+                    record.version.record_type(),
+                    previous,
+                    ip,
+                    synced
+                ),
+                Outcome::Created => info!(
+                    "  {} {}: created with {}{}",
+                    record.name,
+                    record.version.record_type(),
+                    ip,
+                    synced
+                ),
+                Outcome::Duplicate => info!(
+                    "  {} {}: skipped, configured more than once",
+                    record.name,
+                    record.version.record_type()
+                ),
+                Outcome::Failed(e) => {
+                    error!(
+                        "  {} {}: failed: {:#}",
+                        record.name,
+                        record.version.record_type(),
+                        e
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Where addresses come from: the `[ip_source]` most records share, and one source
+/// for each distinct `ip_source` that records have of their own.
+struct IpSources {
+    default: Box<dyn IpSource>,
+    /// A source that cannot be set up fails only the records that use it
+    records: HashMap<IpSourceConfig, Result<Box<dyn IpSource>, Arc<anyhow::Error>>>,
+}
+
+impl IpSources {
+    /// Sets up the sources of `config`'s records once, so that cycles reuse their
+    /// clients. In dry runs every source is simulated, like `default` is.
+    fn new(default: Box<dyn IpSource>, config: &Config, dry_run: bool) -> Self {
+        let mut records = HashMap::new();
+        for source in config.records.iter().filter_map(|r| r.ip_source.as_ref()) {
+            if records.contains_key(source) {
+                continue;
+            }
+            let built: Result<Box<dyn IpSource>, _> = if dry_run {
+                Ok(Box::new(MockIpSource::new()))
+            } else {
+                ip::source_from_config(source, &config.ip_detection, &config.retry).map_err(|e| {
+                    let e = anyhow::Error::new(e).context(format!("Could not set up {source}"));
+                    warn!("{:#}", e);
+                    Arc::new(e)
+                })
+            };
+            records.insert(source.clone(), built);
+        }
+        Self { default, records }
+    }
+
+    fn get(&self, source: Option<&IpSourceConfig>) -> Result<&dyn IpSource, Arc<anyhow::Error>> {
+        let Some(source) = source else {
+            return Ok(self.default.as_ref());
+        };
+        match self
+            .records
+            .get(source)
+            .expect("sources are set up for every configured record")
+        {
+            Ok(built) => Ok(built.as_ref()),
+            Err(e) => Err(e.clone()),
+        }
+    }
+}
+
+/// The Route53 client for each account the records live in: one per assumed role,
+/// and the default credentials for records without a role.
+struct DnsAccounts<'a> {
+    default: &'a dyn DnsOperations,
+    roles: HashMap<AssumeRole, &'a dyn DnsOperations>,
+}
+
+impl<'a> DnsAccounts<'a> {
+    /// Uses `dns` for every record, whatever its role.
+    #[cfg(test)]
+    fn single(dns: &'a dyn DnsOperations) -> Self {
+        Self {
+            default: dns,
+            roles: HashMap::new(),
+        }
+    }
+
+    fn get(&self, role: Option<&AssumeRole>) -> &'a dyn DnsOperations {
+        role.and_then(|role| self.roles.get(role))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Brings every record up to date. Records are independent: one that cannot be
+/// checked or changed is reported as failed in the result, and the rest carry on.
+/// Up to `config.concurrency` records or zones are worked on at the same time.
 async fn run_update(
-    ip_detector: &IpDetector,
-    dns_updater: &dyn DnsOperations,
+    ip_sources: &IpSources,
+    dns: &DnsAccounts<'_>,
     config: &Config,
-) -> Result<()> {
-    info!("Checking current public IP");
-    let current_ip = ip_detector.get_public_ip().await?;
-    info!("Current public IP: {}", current_ip);
+    state: &mut State,
+) -> Result<CycleResult> {
+    let records: Vec<&DnsRecord> = config.records.iter().collect();
+    update_records(ip_sources, dns, config, &records, state).await
+}
 
-    for record in &config.records {
-        info!("Checking DNS record: {}", record.name);
+/// Like [`run_update`], for only some of the configured records.
+async fn update_records(
+    ip_sources: &IpSources,
+    dns: &DnsAccounts<'_>,
+    config: &Config,
+    records: &[&DnsRecord],
+    state: &mut State,
+) -> Result<CycleResult> {
+    // Only look up the sources and address families that some record actually needs
+    let mut current_ips = HashMap::new();
+    for &record in records {
+        for &version in record.record_type.versions() {
+            let key = (record.ip_source.as_ref(), version);
+            if current_ips.contains_key(&key) {
+                continue;
+            }
 
-        match dns_updater
-            .get_current_record_ip(&record.hosted_zone_id, &record.name)
-            .await
-        {
-            Ok(dns_ip) => {
-                if dns_ip != current_ip {
+            let source = match ip_sources.get(record.ip_source.as_ref()) {
+                Ok(source) => source,
+                Err(e) => {
+                    current_ips.insert(key, Err(e));
+                    continue;
+                }
+            };
+            info!(
+                "Checking current {} address ({})",
+                version,
+                source.describe()
+            );
+            let current_ip = match source.get_ip(version).await {
+                Ok(ip) => {
                     info!(
-                        "IP mismatch for {}: DNS={}, Current={}. Updating...",
-                        record.name, dns_ip, current_ip
+                        "Current {} address ({}): {}",
+                        version,
+                        source.describe(),
+                        ip
                     );
+                    Ok(ip)
+                }
+                Err(e) => Err(Arc::new(anyhow::Error::new(e).context(format!(
+                    "Could not get current {} address ({})",
+                    version,
+                    source.describe()
+                )))),
+            };
+            current_ips.insert(key, current_ip);
+        }
+    }
 
-                    dns_updater
-                        .update_record(
-                            &record.hosted_zone_id,
-                            &record.name,
-                            &current_ip,
-                            record.ttl,
-                        )
-                        .await?;
+    // Records whose address Route53 is known to have are left alone until the next
+    // full reconcile, without even finding their zone
+    let now = Utc::now();
+    let known =
+        |record: &DnsRecord, version| match current_ips[&(record.ip_source.as_ref(), version)] {
+            Ok(ip) => state.is_published(
+                &record.name,
+                version,
+                ip,
+                &record_settings(config, record),
+                config.state.reconcile_interval.0,
+                now,
+            ),
+            Err(_) => false,
+        };
 
-                    info!("Successfully updated {} to {}", record.name, current_ip);
-                } else {
-                    info!("IP for {} is up to date: {}", record.name, current_ip);
+    // Find each record's zone, looking it up by name where it is not configured
+    let zones: Vec<Option<Result<String, Arc<anyhow::Error>>>> = stream::iter(records)
+        .map(|&record| {
+            let dns_updater = dns.get(config.role_for(record).as_ref());
+            let needed = !record
+                .record_type
+                .versions()
+                .iter()
+                .all(|&version| known(record, version));
+            async move {
+                if !needed {
+                    return None;
                 }
+                Some(match &record.hosted_zone_id {
+                    Some(zone) => Ok(zone.clone()),
+                    None => retry::with_retries(
+                        &config.retry,
+                        &format!("Finding zone of {}", record.name),
+                        DnsError::is_retryable,
+                        || dns_updater.find_hosted_zone(&record.name),
+                    )
+                    .await
+                    .map_err(|e| {
+                        let context = format!("Could not find hosted zone for {}", record.name);
+                        Arc::new(anyhow::Error::new(e).context(context))
+                    }),
+                })
             }
-            Err(e) => {
-                warn!(
-                    "Could not get current DNS record for {}: {}",
-                    record.name, e
-                );
-                info!(
-                    "Creating new record for {} with IP {}",
-                    record.name, current_ip
+            .instrument(info_span!("record", name = %record.name))
+        })
+        .buffered(config.concurrency)
+        .collect()
+        .await;
+
+    // Every address family of a record is checked on its own and gets its own outcome slot
+    let mut outcomes: Vec<Option<RecordOutcome>> = Vec::new();
+    let mut checks: BTreeMap<ZoneKey, Vec<Check>> = BTreeMap::new();
+    for (&record, zone) in records.iter().zip(zones) {
+        for &version in record.record_type.versions() {
+            let slot = outcomes.len();
+            let current_ip = &current_ips[&(record.ip_source.as_ref(), version)];
+            if known(record, version) {
+                let ip = current_ip.as_ref().ok().copied();
+                debug!(
+                    "IP for {} is unchanged since Route53 last confirmed it: {:?}",
+                    record.name, ip
                 );
+                let unchanged = Outcome::Unchanged { checked: false };
+                outcomes.push(Some(RecordOutcome::new(
+                    config, record, version, ip, unchanged,
+                )));
+                continue;
+            }
+            let zone = zone.as_ref().expect("zones are found for records to check");
+            let failure = match (zone, current_ip) {
+                (Ok(zone), Ok(ip)) => {
+                    let key = (config.role_for(record), zone.clone());
+                    checks.entry(key).or_default().push(Check {
+                        slot,
+                        record,
+                        version,
+                        ip: *ip,
+                    });
+                    outcomes.push(None);
+                    continue;
+                }
+                (Err(e), _) | (_, Err(e)) => Outcome::Failed(e.clone()),
+            };
+            let ip = current_ip.as_ref().ok().copied();
+            outcomes.push(Some(RecordOutcome::new(
+                config, record, version, ip, failure,
+            )));
+        }
+    }
 
-                dns_updater
-                    .update_record(
-                        &record.hosted_zone_id,
-                        &record.name,
-                        &current_ip,
-                        record.ttl,
-                    )
-                    .await?;
+    let mut lookups: Vec<(&ZoneKey, &Check, CurrentRecord)> = stream::iter(&checks)
+        .map(|(key, checks)| {
+            let (role, zone) = key;
+            let dns_updater = dns.get(role.as_ref());
+            async move {
+                let found = current_records(dns_updater, zone, checks, config).await;
+                checks
+                    .iter()
+                    .zip(found)
+                    .map(|(check, found)| (key, check, found))
+                    .collect::<Vec<_>>()
+            }
+        })
+        .buffer_unordered(config.concurrency)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    // Back in configuration order, so the first of duplicate entries wins
+    lookups.sort_by_key(|(_, check, _)| check.slot);
 
+    // Changes are collected per hosted zone and submitted together, so a cycle costs
+    // one ChangeResourceRecordSets call per zone rather than one per record. Each
+    // change remembers the slot of its outcome.
+    let mut pending: BTreeMap<&ZoneKey, Vec<(usize, RecordChange)>> = BTreeMap::new();
+    for (key, check, found) in lookups {
+        let Check {
+            slot,
+            record,
+            version,
+            ip: current_ip,
+        } = *check;
+        let outcome = match found {
+            Ok(Some(dns_ip)) if dns_ip == current_ip => {
+                info!("IP for {} is up to date: {}", record.name, current_ip);
+                Outcome::Unchanged { checked: true }
+            }
+            Ok(Some(dns_ip)) => {
                 info!(
-                    "Successfully created {} with IP {}",
-                    record.name, current_ip
+                    "IP mismatch for {}: DNS={}, Current={}. Updating...",
+                    record.name, dns_ip, current_ip
+                );
+                Outcome::Updated { previous: dns_ip }
+            }
+            Ok(None) => {
+                info!(
+                    "No {} record for {} yet. Creating it with IP {}",
+                    version.record_type(),
+                    record.name,
+                    current_ip
+                );
+                Outcome::Created
+            }
+            Err(e) => Outcome::Failed(e),
+        };
+
+        if matches!(outcome, Outcome::Updated { .. } | Outcome::Created) {
+            let changes = pending.entry(key).or_default();
+            // Route53 rejects a batch that changes the same record twice
+            if changes
+                .iter()
+                .any(|(_, c)| c.name == record.name && IpVersion::of(&c.ip) == version)
+            {
+                warn!(
+                    "{} ({}) is configured more than once; only the first entry is used",
+                    record.name,
+                    version.record_type()
                 );
+                outcomes[slot] = Some(RecordOutcome::new(
+                    config,
+                    record,
+                    version,
+                    Some(current_ip),
+                    Outcome::Duplicate,
+                ));
+                continue;
             }
+            changes.push((
+                slot,
+                RecordChange {
+                    name: record.name.clone(),
+                    ip: current_ip,
+                    ttl: record.ttl,
+                },
+            ));
         }
+        outcomes[slot] = Some(RecordOutcome::new(
+            config,
+            record,
+            version,
+            Some(current_ip),
+            outcome,
+        ));
     }
 
-    Ok(())
+    let submitted: Vec<Vec<_>> = stream::iter(&pending)
+        .map(|((role, zone), changes)| {
+            let dns_updater = dns.get(role.as_ref());
+            async move {
+                let mut results = Vec::new();
+                for chunk in changes.chunks(MAX_CHANGES_PER_BATCH) {
+                    let batch: Vec<RecordChange> =
+                        chunk.iter().map(|(_, change)| change.clone()).collect();
+                    let submitted = submit_batch(dns_updater, zone, &batch, config).await;
+                    let rejected = submitted.as_ref().is_err_and(|e| {
+                        matches!(
+                            e.downcast_ref::<DnsError>(),
+                            Some(DnsError::Rejected { .. })
+                        )
+                    });
+                    if !rejected || chunk.len() == 1 {
+                        let submitted = submitted.map_err(Arc::new);
+                        results.extend(chunk.iter().map(|(slot, _)| (*slot, submitted.clone())));
+                        continue;
+                    }
+
+                    // Route53 rejects a whole batch for one bad change (e.g. a name that
+                    // already has a CNAME), so find out which one it is
+                    warn!(
+                        "Zone {} rejected {} changes at once, submitting them one by one",
+                        zone,
+                        chunk.len()
+                    );
+                    for (slot, change) in chunk {
+                        let submitted =
+                            submit_batch(dns_updater, zone, std::slice::from_ref(change), config)
+                                .await
+                                .map_err(Arc::new);
+                        results.push((*slot, submitted));
+                    }
+                }
+                results
+            }
+            .instrument(info_span!("zone", id = %zone))
+        })
+        .buffer_unordered(config.concurrency)
+        .collect()
+        .await;
+    for (slot, submitted) in submitted.into_iter().flatten() {
+        let outcome = outcomes[slot]
+            .as_mut()
+            .expect("submitted changes have an outcome");
+        match submitted {
+            Ok(in_sync_after) => outcome.in_sync_after = in_sync_after,
+            Err(e) => outcome.outcome = Outcome::Failed(e),
+        }
+    }
+
+    let result = CycleResult {
+        records: outcomes
+            .into_iter()
+            .map(|outcome| outcome.expect("every record has an outcome"))
+            .collect(),
+    };
+    result.remember(state, now);
+    Ok(result)
+}
+
+/// A hosted zone, reached through the account of a role (or the default credentials).
+type ZoneKey = (Option<AssumeRole>, String);
+
+/// One address family of a configured record, to be compared with its zone.
+struct Check<'a> {
+    /// Where the outcome goes in the cycle result
+    slot: usize,
+    record: &'a DnsRecord,
+    version: IpVersion,
+    ip: IpAddr,
+}
+
+/// The address a record has in Route53, `None` if it does not exist yet.
+type CurrentRecord = Result<Option<IpAddr>, Arc<anyhow::Error>>;
+
+/// Gets the current value of each checked record in `zone`: with one listing of the
+/// zone when that takes fewer `ListResourceRecordSets` calls than looking up each
+/// record, and with a lookup of just the record otherwise.
+async fn current_records(
+    dns_updater: &dyn DnsOperations,
+    zone: &str,
+    checks: &[Check<'_>],
+    config: &Config,
+) -> Vec<CurrentRecord> {
+    let retry_config = &config.retry;
+    let found = |check: &Check, lookup: Result<IpAddr, DnsError>| match lookup {
+        Ok(ip) => Ok(Some(ip)),
+        Err(DnsError::RecordNotFound { .. }) => Ok(None),
+        Err(e) => Err(Arc::new(anyhow::Error::new(e).context(format!(
+            "Could not get current DNS record for {}",
+            check.record.name
+        )))),
+    };
+
+    let list = checks.len() > 1
+        && match retry::with_retries(
+            retry_config,
+            &format!("Counting records of zone {zone}"),
+            DnsError::is_retryable,
+            || dns_updater.listing_pages(zone),
+        )
+        .instrument(info_span!("zone", id = %zone))
+        .await
+        {
+            Ok(pages) => pages < checks.len(),
+            Err(e) => {
+                warn!(
+                    "Looking up records of zone {} one by one: {:#}",
+                    zone,
+                    anyhow::Error::new(e)
+                );
+                false
+            }
+        };
+    if !list {
+        return stream::iter(checks)
+            .map(|check| async move {
+                let lookup = retry::with_retries(
+                    retry_config,
+                    &format!("Looking up {}", check.record.name),
+                    DnsError::is_retryable,
+                    || dns_updater.get_current_record_ip(zone, &check.record.name, check.version),
+                )
+                .instrument(info_span!("record", name = %check.record.name))
+                .await;
+                found(check, lookup)
+            })
+            .buffered(config.concurrency)
+            .collect()
+            .await;
+    }
+
+    info!(
+        "Listing zone {} to check {} records in it",
+        zone,
+        checks.len()
+    );
+    let listing = retry::with_retries(
+        retry_config,
+        &format!("Listing records of zone {zone}"),
+        DnsError::is_retryable,
+        || dns_updater.list_address_records(zone),
+    )
+    .instrument(info_span!("zone", id = %zone))
+    .await;
+    match listing {
+        Ok(records) => checks
+            .iter()
+            .map(|check| found(check, records.lookup(&check.record.name, check.version)))
+            .collect(),
+        Err(e) => {
+            let e = Arc::new(
+                anyhow::Error::new(e).context(format!("Could not list records of zone {zone}")),
+            );
+            checks.iter().map(|_| Err(e.clone())).collect()
+        }
+    }
 }
 
-async fn run_continuous(
-    ip_detector: &IpDetector,
+/// Submits one batch of changes to `zone`, waiting for it to propagate if configured
+/// to, and returns how long that took.
+async fn submit_batch(
     dns_updater: &dyn DnsOperations,
+    zone: &str,
+    batch: &[RecordChange],
     config: &Config,
-) -> Result<()> {
-    let mut interval = tokio::time::interval(Duration::from_secs(300)); // Fixed 5-minute interval
+) -> Result<Option<Duration>> {
+    let what = format!("Updating {} record(s) in zone {}", batch.len(), zone);
+    let change_id = retry::with_retries(&config.retry, &what, DnsError::is_retryable, || {
+        dns_updater.update_records(zone, batch)
+    })
+    .await
+    .with_context(|| format!("Could not update DNS records in zone {zone}"))?;
+    info!("Submitted {} change(s) to zone {}", batch.len(), zone);
+
+    let propagation = &config.propagation;
+    if !propagation.wait_for_sync {
+        return Ok(None);
+    }
+    let Some(change_id) = change_id else {
+        warn!(
+            "Route53 returned no change ID for zone {}; not waiting for it to propagate",
+            zone
+        );
+        return Ok(None);
+    };
+
+    info!("Waiting for change {} to propagate", change_id);
+    // Not retried: starting over would lose track of how long it has taken
+    let took = dns_updater
+        .wait_for_sync(zone, &change_id, Duration::from_secs(propagation.timeout))
+        .await
+        .with_context(|| format!("Could not confirm changes to zone {zone}"))?;
+    info!(
+        "Change {} is in sync on all Route53 name servers after {:.1}s",
+        change_id,
+        took.as_secs_f64()
+    );
+    Ok(Some(took))
+}
+
+async fn run_continuous(setup: &mut Setup, state: &mut State, signals: &mut Signals) -> Result<()> {
+    let mut schedule = Schedule::new(&setup.config, Instant::now());
+    let mut network = NetworkChanges::subscribe(&setup.config.network_changes);
+    let mut received = Vec::new();
+    // Records whose credentials were refused; they fail the same way every cycle, so
+    // they are left alone until the config is reloaded or a check is asked for
+    let mut denied: BTreeSet<EntryKey> = BTreeSet::new();
 
     loop {
-        interval.tick().await;
+        let config = &setup.config;
+        let is_denied = |record: &DnsRecord| {
+            denied.contains(&entry_key(&record.name, &record_settings(config, record)))
+        };
+        let due: Vec<&DnsRecord> = schedule
+            .take_due(Instant::now())
+            .into_iter()
+            .map(|i| &config.records[i])
+            .filter(|&record| !is_denied(record))
+            .collect();
 
-        if let Err(e) = run_update(ip_detector, dns_updater, config).await {
-            error!("Error during update cycle: {}", e);
+        if !due.is_empty() {
+            let dns = setup.dns();
+            let cycle = update_records(&setup.ip_sources, &dns, &setup.config, &due, state);
+            let shutdown_timeout = setup.config.shutdown_timeout;
+            match finish_cycle(cycle, signals, shutdown_timeout, &mut received).await {
+                Some(Ok(result)) => {
+                    result.log();
+                    save_state(state).await;
+                    for (record, e) in result.auth_failures() {
+                        error!("Not checking {} again until reloaded: {:#}", record.name, e);
+                        denied.insert(entry_key(&record.name, &record.settings));
+                    }
+                    // With nothing left to update, stop and make it obvious
+                    if let Some((_, e)) = result.auth_failures().last() {
+                        let config = &setup.config;
+                        if config.records.iter().all(|record| {
+                            denied.contains(&entry_key(
+                                &record.name,
+                                &record_settings(config, record),
+                            ))
+                        }) {
+                            error!("Stopping: {:#}", e);
+                            bail!("{:#}", e);
+                        }
+                    }
+                }
+                Some(Err(e)) => error!("Error during update cycle: {:#}", e),
+                None => warn!(
+                    "The update cycle did not finish within {}, stopping anyway",
+                    shutdown_timeout
+                ),
+            }
         }
+
+        if received.is_empty() {
+            let wake = schedule.next_wake().context("No records to schedule")?;
+            info!(
+                "Next check in {:.0}s",
+                wake.saturating_duration_since(Instant::now()).as_secs_f64()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep_until(wake.into()) => {}
+                change = network.next() => {
+                    info!("Network changed ({}), checking all records", change);
+                    schedule.set_all_due(Instant::now());
+                }
+                signal = signals.recv() => received.push(signal),
+            }
+        }
+
+        for signal in std::mem::take(&mut received) {
+            match signal {
+                Signal::Shutdown => {
+                    info!("Shutting down");
+                    return Ok(());
+                }
+                Signal::Reload => match setup.reload().await {
+                    Ok(old) => {
+                        denied.clear();
+                        let changes = old.diff(&setup.config);
+                        if changes.is_empty() {
+                            info!("Reloaded the configuration, nothing changed");
+                        }
+                        for change in changes {
+                            info!("Configuration changed: {}", change);
+                        }
+                        schedule.reload(&old, &setup.config, Instant::now());
+                        if setup.config.network_changes != old.network_changes {
+                            network = NetworkChanges::subscribe(&setup.config.network_changes);
+                        }
+                        if setup.config.state.path != old.state.path {
+                            *state = load_state(&setup.config, setup.source.dry_run).await;
+                        }
+                    }
+                    Err(e) => error!("Keeping the current configuration: {:#}", e),
+                },
+                Signal::CheckNow => {
+                    info!("Checking all records");
+                    denied.clear();
+                    schedule.set_all_due(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+/// Runs an update cycle, keeping the signals that arrive meanwhile for afterwards.
+/// Once told to shut down, the cycle gets `shutdown_timeout` to finish (so that no
+/// change batch is cut off halfway), and `None` is returned if it does not.
+async fn finish_cycle<T>(
+    cycle: impl Future<Output = T>,
+    signals: &mut Signals,
+    shutdown_timeout: HumanDuration,
+    received: &mut Vec<Signal>,
+) -> Option<T> {
+    tokio::pin!(cycle);
+    loop {
+        tokio::select! {
+            result = &mut cycle => return Some(result),
+            signal = signals.recv() => {
+                received.push(signal);
+                if signal == Signal::Shutdown {
+                    break;
+                }
+            }
+        }
+    }
+    info!(
+        "Finishing the update cycle before shutting down (at most {})",
+        shutdown_timeout
+    );
+    tokio::time::timeout(shutdown_timeout.0, cycle).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{
+        AssumeRoleConfig, AwsConfig, CommandSourceConfig, HttpServiceConfig, HumanDuration,
+        IpServiceConfig, RecordType, ResponseFormat, StaticSourceConfig,
+    };
+    use dns::ZoneRecords;
+    use state::RecordState;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn record(name: &str, record_type: RecordType, ip_source: Option<IpSourceConfig>) -> DnsRecord {
+        DnsRecord {
+            name: name.to_string(),
+            hosted_zone_id: Some("Z1234567890ABC".to_string()),
+            ttl: 300,
+            record_type,
+            ip_source,
+            assume_role: AssumeRoleConfig::default(),
+            interval: None,
+        }
+    }
+
+    /// Answers lookups from a script (reporting records missing once it runs out) and
+    /// zone listings with fixed records, and remembers the updates and propagation
+    /// waits it is asked for.
+    struct ScriptedDns {
+        lookups: Mutex<VecDeque<Result<IpAddr, DnsError>>>,
+        /// Name, family and value of each record in every listed zone
+        zone_records: Vec<(&'static str, IpVersion, &'static str)>,
+        /// How many calls listing a zone takes
+        listing_pages: usize,
+        /// Zones whose records cannot be read with these credentials
+        denied_zones: Vec<&'static str>,
+        /// Records whose changes Route53 rejects, along with the rest of their batch
+        rejected_names: Vec<&'static str>,
+        listed: Mutex<Vec<String>>,
+        updates: Mutex<Vec<(String, IpAddr)>>,
+        /// Zone and number of changes of each submitted batch; shared, so that it can
+        /// be checked once a `Setup` owns this
+        batches: Arc<Mutex<Vec<(String, usize)>>>,
+        waited_for: Mutex<Vec<String>>,
+        /// How long submitting a batch takes
+        update_delay: Duration,
+        /// How long looking up a record takes
+        lookup_delay: Duration,
+        lookups_in_flight: AtomicUsize,
+        most_lookups_in_flight: AtomicUsize,
+    }
+
+    impl ScriptedDns {
+        fn new(lookups: Vec<Result<IpAddr, DnsError>>) -> Self {
+            Self {
+                lookups: Mutex::new(lookups.into()),
+                zone_records: Vec::new(),
+                listing_pages: 1,
+                denied_zones: Vec::new(),
+                rejected_names: Vec::new(),
+                listed: Mutex::new(Vec::new()),
+                updates: Mutex::new(Vec::new()),
+                batches: Arc::default(),
+                waited_for: Mutex::new(Vec::new()),
+                update_delay: Duration::ZERO,
+                lookup_delay: Duration::ZERO,
+                lookups_in_flight: AtomicUsize::new(0),
+                most_lookups_in_flight: AtomicUsize::new(0),
+            }
+        }
+
+        fn unused_lookups(&self) -> usize {
+            self.lookups.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DnsOperations for ScriptedDns {
+        async fn get_current_record_ip(
+            &self,
+            hosted_zone_id: &str,
+            record_name: &str,
+            version: IpVersion,
+        ) -> Result<IpAddr, DnsError> {
+            if self.denied_zones.contains(&hosted_zone_id) {
+                return Err(access_denied());
+            }
+            let in_flight = self.lookups_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_lookups_in_flight
+                .fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(self.lookup_delay).await;
+            self.lookups_in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.lookups.lock().unwrap().pop_front().unwrap_or_else(|| {
+                Err(DnsError::RecordNotFound {
+                    name: record_name.to_string(),
+                    record_type: version.record_type(),
+                })
+            })
+        }
+
+        async fn list_address_records(
+            &self,
+            hosted_zone_id: &str,
+        ) -> Result<ZoneRecords, DnsError> {
+            if self.denied_zones.contains(&hosted_zone_id) {
+                return Err(access_denied());
+            }
+            self.listed.lock().unwrap().push(hosted_zone_id.to_string());
+            let mut records = ZoneRecords::default();
+            for (name, version, value) in &self.zone_records {
+                records.insert(name, *version, value);
+            }
+            Ok(records)
+        }
+
+        async fn listing_pages(&self, _hosted_zone_id: &str) -> Result<usize, DnsError> {
+            Ok(self.listing_pages)
+        }
+
+        async fn find_hosted_zone(&self, record_name: &str) -> Result<String, DnsError> {
+            // Pretend every parent domain is a zone named after itself
+            let (_, parent) = record_name.split_once('.').unwrap();
+            Ok(format!("Z-{parent}"))
+        }
+
+        async fn update_records(
+            &self,
+            hosted_zone_id: &str,
+            changes: &[RecordChange],
+        ) -> Result<Option<String>, DnsError> {
+            tokio::time::sleep(self.update_delay).await;
+            if changes
+                .iter()
+                .any(|c| self.rejected_names.contains(&c.name.as_str()))
+            {
+                return Err(DnsError::Rejected {
+                    operation: "ChangeResourceRecordSets",
+                    zone: hosted_zone_id.to_string(),
+                    source: "InvalidChangeBatch".into(),
+                });
+            }
+            self.updates
+                .lock()
+                .unwrap()
+                .extend(changes.iter().map(|c| (c.name.clone(), c.ip)));
+            let mut batches = self.batches.lock().unwrap();
+            batches.push((hosted_zone_id.to_string(), changes.len()));
+            Ok(Some(format!("C{}", batches.len())))
+        }
+
+        async fn wait_for_sync(
+            &self,
+            _hosted_zone_id: &str,
+            change_id: &str,
+            _timeout: Duration,
+        ) -> Result<Duration, DnsError> {
+            self.waited_for.lock().unwrap().push(change_id.to_string());
+            Ok(Duration::from_secs(42))
+        }
+    }
+
+    /// Simulated addresses for records without a source of their own, and the (offline)
+    /// sources of those that have one.
+    fn sources(config: &Config) -> IpSources {
+        IpSources::new(Box::new(MockIpSource::new()), config, false)
+    }
+
+    fn single_record_config() -> Config {
+        Config {
+            records: vec![record("new.example.com", RecordType::A, None)],
+            ..Config::default()
+        }
+    }
+
+    /// What `state` has for the A record `name`, as it is configured in `config`.
+    fn stored<'a>(state: &'a State, config: &Config, name: &str) -> Option<&'a RecordState> {
+        let record = config.records.iter().find(|r| r.name == name)?;
+        state.get(name, IpVersion::V4, &record_settings(config, record))
+    }
+
+    /// Continuous mode with `config` and `dns`, reloading from `path` in dry-run mode.
+    fn setup(config: Config, dns: ScriptedDns, path: &str) -> Setup {
+        Setup {
+            source: ConfigSource {
+                path: path.to_string(),
+                wait_for_sync: false,
+                dry_run: true,
+            },
+            ip_sources: sources(&config),
+            config,
+            dns: Box::new(dns),
+            roles: Vec::new(),
+        }
+    }
+
+    fn transient() -> DnsError {
+        DnsError::Transient {
+            operation: "ListResourceRecordSets",
+            zone: "Z1234567890ABC".to_string(),
+            source: "connection reset".into(),
+        }
+    }
+
+    fn access_denied() -> DnsError {
+        DnsError::AccessDenied {
+            operation: "ListResourceRecordSets",
+            zone: "Z1234567890ABC".to_string(),
+            source: "AccessDenied".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_update_creates_only_missing_records() {
+        let config = single_record_config();
+        let ip_source = MockIpSource::new();
+
+        let missing = ScriptedDns::new(Vec::new());
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&missing),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *missing.updates.lock().unwrap(),
+            [("new.example.com".to_string(), ip_source.v4)]
+        );
+        assert_eq!(result.records.len(), 1);
+        assert!(matches!(result.records[0].outcome, Outcome::Created));
+        assert_eq!(result.records[0].in_sync_after, None);
+        assert!(missing.waited_for.lock().unwrap().is_empty());
+        assert_eq!(result.exit_code(), ExitCode::SUCCESS);
+
+        // Any other lookup failure must not be mistaken for a missing record
+        let broken = ScriptedDns::new(vec![Err(DnsError::InvalidRecord {
+            name: "new.example.com".to_string(),
+            value: "not-an-ip".to_string(),
+        })]);
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&broken),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        let err = result.records[0].failed().unwrap();
+        assert!(err
+            .to_string()
+            .contains("Could not get current DNS record for new.example.com"));
+        assert!(broken.updates.lock().unwrap().is_empty());
+        assert_eq!(result.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[tokio::test]
+    async fn test_run_update_waits_for_sync() {
+        let mut config = single_record_config();
+        config.propagation.wait_for_sync = true;
+        let ip_source = MockIpSource::new();
+
+        let dns = ScriptedDns::new(vec![Ok("81.2.69.160".parse().unwrap())]);
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*dns.waited_for.lock().unwrap(), ["C1"]);
+
+        let change = &result.records[0];
+        assert_eq!(change.name, "new.example.com");
+        assert_eq!(change.version, IpVersion::V4);
+        assert_eq!(change.ip, Some(ip_source.v4));
+        assert!(matches!(
+            change.outcome,
+            Outcome::Updated { previous } if previous.to_string() == "81.2.69.160"
+        ));
+        assert_eq!(change.in_sync_after, Some(Duration::from_secs(42)));
+    }
+
+    #[tokio::test]
+    async fn test_run_update_batches_changes_per_zone() {
+        let mut other_zone = record("www.example.org", RecordType::A, None);
+        other_zone.hosted_zone_id = Some("Z0987654321XYZ".to_string());
+        let mut looked_up = record("mail.example.net", RecordType::A, None);
+        looked_up.hosted_zone_id = None;
+        let config = Config {
+            records: vec![
+                record("dual.example.com", RecordType::Both, None),
+                other_zone,
+                record("www.example.com", RecordType::A, None),
+                record("www.example.com", RecordType::A, None),
+                looked_up,
+            ],
+            ..Config::default()
+        };
+
+        let dns = ScriptedDns::new(Vec::new());
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        // Zones are submitted concurrently, so in no particular order
+        dns.batches.lock().unwrap().sort();
+        assert_eq!(
+            *dns.batches.lock().unwrap(),
+            [
+                ("Z-example.net".to_string(), 1),
+                ("Z0987654321XYZ".to_string(), 1),
+                ("Z1234567890ABC".to_string(), 3)
+            ]
+        );
+        // The second www.example.com entry is reported, but not submitted
+        assert_eq!(result.records.len(), 6);
+        assert!(matches!(result.records[4].outcome, Outcome::Duplicate));
+        assert_eq!(result.exit_code(), ExitCode::SUCCESS);
+
+        // Zones with more changes than fit in one request are split up
+        let config = Config {
+            records: (0..=MAX_CHANGES_PER_BATCH)
+                .map(|i| record(&format!("host{i}.example.com"), RecordType::A, None))
+                .collect(),
+            ..Config::default()
+        };
+        let dns = ScriptedDns::new(Vec::new());
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *dns.batches.lock().unwrap(),
+            [
+                ("Z1234567890ABC".to_string(), MAX_CHANGES_PER_BATCH),
+                ("Z1234567890ABC".to_string(), 1)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_update_uses_role_accounts() {
+        let role = |arn: &str| AssumeRoleConfig {
+            role_arn: Some(arn.to_string()),
+            ..AssumeRoleConfig::default()
+        };
+        let mut other_account = record("other.example.org", RecordType::A, None);
+        other_account.assume_role = role("arn:aws:iam::222222222222:role/dns");
+        let config = Config {
+            records: vec![
+                record("www.example.com", RecordType::A, None),
+                other_account,
+            ],
+            aws: AwsConfig {
+                assume_role: role("arn:aws:iam::111111111111:role/dns"),
+                ..AwsConfig::default()
+            },
+            ..Config::default()
+        };
+        let ip_source = MockIpSource::new();
+
+        let default = ScriptedDns::new(Vec::new());
+        let first = ScriptedDns::new(Vec::new());
+        let second = ScriptedDns::new(Vec::new());
+        let dns = DnsAccounts {
+            default: &default,
+            roles: HashMap::from([
+                (
+                    config.aws.assume_role.role().unwrap(),
+                    &first as &dyn DnsOperations,
+                ),
+                (
+                    config.records[1].assume_role.role().unwrap(),
+                    &second as &dyn DnsOperations,
+                ),
+            ]),
+        };
+        run_update(&sources(&config), &dns, &config, &mut State::default())
+            .await
+            .unwrap();
+
+        // Records without a role of their own use the one from [aws]
+        assert!(default.updates.lock().unwrap().is_empty());
+        assert_eq!(
+            *first.updates.lock().unwrap(),
+            [("www.example.com".to_string(), ip_source.v4)]
+        );
+        assert_eq!(
+            *second.updates.lock().unwrap(),
+            [("other.example.org".to_string(), ip_source.v4)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_update_retries_transient_errors() {
+        let mut config = single_record_config();
+        config.retry.base_delay_ms = 1;
+        let ip_source = MockIpSource::new();
+
+        let flaky = ScriptedDns::new(vec![Err(transient()), Ok(ip_source.v4)]);
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&flaky),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(flaky.unused_lookups(), 0);
+        // The record turned out to be up to date
+        assert!(flaky.updates.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_update_continues_after_failures() {
+        let ipv4_only = IpSourceConfig::Static(StaticSourceConfig {
+            addresses: vec!["81.2.69.160".parse().unwrap()],
+        });
+        let config = Config {
+            records: vec![
+                record("current.example.com", RecordType::A, None),
+                record("broken.example.com", RecordType::A, None),
+                record("static.example.com", RecordType::Both, Some(ipv4_only)),
+                record("new.example.com", RecordType::A, None),
+            ],
+            ..Config::default()
+        };
+
+        let dns = ScriptedDns {
+            zone_records: vec![
+                ("current.example.com.", IpVersion::V4, "203.0.113.10"),
+                ("broken.example.com.", IpVersion::V4, "not-an-ip"),
+            ],
+            ..ScriptedDns::new(Vec::new())
+        };
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        let outcomes: Vec<_> = result
+            .records
+            .iter()
+            .map(|r| {
+                let outcome = match &r.outcome {
+                    Outcome::Unchanged { .. } => "unchanged",
+                    Outcome::Updated { .. } => "updated",
+                    Outcome::Created => "created",
+                    Outcome::Duplicate => "duplicate",
+                    Outcome::Failed(_) => "failed",
+                };
+                (r.name.as_str(), r.version.record_type(), outcome)
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("current.example.com", "A", "unchanged"),
+                ("broken.example.com", "A", "failed"),
+                ("static.example.com", "A", "created"),
+                ("static.example.com", "AAAA", "failed"),
+                ("new.example.com", "A", "created"),
+            ]
+        );
+        assert!(result.records[3]
+            .failed()
+            .unwrap()
+            .to_string()
+            .contains("Could not get current IPv6 address"));
+        assert_eq!(dns.updates.lock().unwrap().len(), 2);
+        assert_eq!(result.exit_code(), ExitCode::from(2));
+        // All records of the zone were checked with a single listing
+        assert_eq!(*dns.listed.lock().unwrap(), ["Z1234567890ABC"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_update_isolates_rejected_changes() {
+        let config = Config {
+            records: vec![
+                record("www.example.com", RecordType::A, None),
+                record("cname.example.com", RecordType::A, None),
+                record("mail.example.com", RecordType::A, None),
+            ],
+            ..Config::default()
+        };
+        let dns = ScriptedDns {
+            rejected_names: vec!["cname.example.com"],
+            ..ScriptedDns::new(Vec::new())
+        };
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        // Only the change Route53 objects to fails; the others go through on their own
+        assert!(matches!(result.records[0].outcome, Outcome::Created));
+        assert!(result.records[1].failed().is_some());
+        assert!(matches!(result.records[2].outcome, Outcome::Created));
+        assert_eq!(
+            *dns.batches.lock().unwrap(),
+            [
+                ("Z1234567890ABC".to_string(), 1),
+                ("Z1234567890ABC".to_string(), 1)
+            ]
+        );
+        assert_eq!(result.exit_code(), ExitCode::from(2));
+    }
+
+    #[tokio::test]
+    async fn test_run_update_lists_zones_only_when_cheaper() {
+        let config = Config {
+            records: vec![
+                record("www.example.com", RecordType::A, None),
+                record("mail.example.com", RecordType::A, None),
+            ],
+            ..Config::default()
+        };
+        let ip_source = MockIpSource::new();
+
+        // Two lookups beat listing a zone that takes three pages, and run side by side
+        let large = ScriptedDns {
+            listing_pages: 3,
+            lookup_delay: Duration::from_millis(50),
+            ..ScriptedDns::new(vec![Ok(ip_source.v4), Ok(ip_source.v4)])
+        };
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&large),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert!(large.listed.lock().unwrap().is_empty());
+        assert_eq!(large.unused_lookups(), 0);
+        assert_eq!(large.most_lookups_in_flight.load(Ordering::SeqCst), 2);
+
+        // ...but not one that fits on a single page
+        let small = ScriptedDns::new(vec![Ok(ip_source.v4), Ok(ip_source.v4)]);
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&small),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*small.listed.lock().unwrap(), ["Z1234567890ABC"]);
+        assert_eq!(small.unused_lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_update_skips_records_known_from_state() {
+        let mut config = single_record_config();
+        let ip_source = MockIpSource::new();
+        let mut state = State::default();
+
+        let dns = ScriptedDns::new(vec![Ok(ip_source.v4)]);
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            result.records[0].outcome,
+            Outcome::Unchanged { checked: true }
+        ));
+
+        // Nothing changed since Route53 confirmed the address, so it is not asked again
+        let dns = ScriptedDns::new(vec![Ok(ip_source.v4)]);
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            result.records[0].outcome,
+            Outcome::Unchanged { checked: false }
+        ));
+        assert_eq!(dns.unused_lookups(), 1);
+
+        // ...until the next full reconcile is due
+        config.state.reconcile_interval = HumanDuration::from_secs(0);
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(dns.unused_lookups(), 0);
+
+        // A record whose published address is not the current one is checked and fixed
+        config.state.reconcile_interval = HumanDuration::from_secs(86_400);
+        let previous: IpAddr = "81.2.69.160".parse().unwrap();
+        let settings = record_settings(&config, &config.records[0]);
+        state.published(
+            "new.example.com",
+            IpVersion::V4,
+            previous,
+            settings.clone(),
+            Utc::now(),
+        );
+        let dns = ScriptedDns::new(vec![Ok(previous)]);
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *dns.updates.lock().unwrap(),
+            [("new.example.com".to_string(), ip_source.v4)]
+        );
+        assert!(state.is_published(
+            "new.example.com",
+            IpVersion::V4,
+            ip_source.v4,
+            &settings,
+            Duration::from_secs(60),
+            Utc::now()
+        ));
+
+        // So is one configured differently since, even with the same address
+        config.records[0].ttl = 60;
+        let dns = ScriptedDns::new(vec![Ok(ip_source.v4)]);
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(dns.unused_lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures_are_fatal() {
+        let config = single_record_config();
+        let ip_source = MockIpSource::new();
+
+        let denied = ScriptedDns::new(vec![Err(access_denied()), Ok(ip_source.v4)]);
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&denied),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.auth_failures().count(), 1);
+        // Not retried, and certainly not "created"
+        assert_eq!(denied.unused_lookups(), 1);
+        assert!(denied.updates.lock().unwrap().is_empty());
+
+        // Continuous mode gives up instead of failing every cycle
+        let denied = ScriptedDns::new(vec![Err(access_denied())]);
+        let (_sender, mut signals) = Signals::channel();
+        assert!(run_continuous(
+            &mut setup(config, denied, "config.toml"),
+            &mut State::default(),
+            &mut signals
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures_only_stop_their_records() {
+        let mut other_account = record("other.example.org", RecordType::A, None);
+        other_account.assume_role = AssumeRoleConfig {
+            role_arn: Some("arn:aws:iam::222222222222:role/dns".to_string()),
+            ..AssumeRoleConfig::default()
+        };
+        let config = Config {
+            records: vec![
+                other_account,
+                record("new.example.com", RecordType::A, None),
+            ],
+            ..Config::default()
+        };
+        let role = config.records[0].assume_role.role().unwrap();
+        let mut setup = setup(config, ScriptedDns::new(Vec::new()), "config.toml");
+        let denied = ScriptedDns::new(vec![Err(access_denied())]);
+        setup.roles = vec![(role, Box::new(denied))];
+        let mut state = State::default();
+        let (sender, mut signals) = Signals::channel();
+
+        let shutdown = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            sender.send(Signal::Shutdown).unwrap();
+        };
+        let (result, ()) = tokio::join!(
+            run_continuous(&mut setup, &mut state, &mut signals),
+            shutdown
+        );
+        // The record in the other account kept running
+        result.unwrap();
+        assert!(stored(&state, &setup.config, "new.example.com").is_some());
+        let other = stored(&state, &setup.config, "other.example.org").unwrap();
+        assert_eq!(other.published, None);
+    }
+
+    #[tokio::test]
+    async fn test_auth_failures_only_stop_their_zone() {
+        let mut denied_zone = record("home.example.com", RecordType::A, None);
+        denied_zone.hosted_zone_id = Some("ZDENIED".to_string());
+        let mut config = Config {
+            records: vec![denied_zone, record("home.example.com", RecordType::A, None)],
+            interval: HumanDuration(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        config.state.reconcile_interval = HumanDuration::from_secs(0);
+        let dns = ScriptedDns {
+            denied_zones: vec!["ZDENIED"],
+            ..ScriptedDns::new(Vec::new())
+        };
+        let batches = dns.batches.clone();
+        let mut setup = setup(config, dns, "config.toml");
+        let mut state = State::default();
+        let (sender, mut signals) = Signals::channel();
+
+        let shutdown = async {
+            tokio::time::sleep(Duration::from_millis(230)).await;
+            sender.send(Signal::Shutdown).unwrap();
+        };
+        let (result, ()) = tokio::join!(
+            run_continuous(&mut setup, &mut state, &mut signals),
+            shutdown
+        );
+        // The same name in the other zone is still checked and updated every cycle
+        result.unwrap();
+        let batches = batches.lock().unwrap();
+        assert!(batches.len() >= 2, "{batches:?}");
+        assert!(batches.iter().all(|(zone, _)| zone == "Z1234567890ABC"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finishes_cycle() {
+        for (shutdown_timeout, finished) in [(5, true), (0, false)] {
+            let config = Config {
+                shutdown_timeout: HumanDuration::from_secs(shutdown_timeout),
+                ..single_record_config()
+            };
+            let slow = ScriptedDns {
+                update_delay: Duration::from_millis(200),
+                ..ScriptedDns::new(Vec::new())
+            };
+            let mut setup = setup(config, slow, "config.toml");
+            let mut state = State::default();
+            let (sender, mut signals) = Signals::channel();
+
+            let shutdown = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                sender.send(Signal::Shutdown).unwrap();
+            };
+            let (result, ()) = tokio::join!(
+                run_continuous(&mut setup, &mut state, &mut signals),
+                shutdown
+            );
+            result.unwrap();
+            assert_eq!(
+                stored(&state, &setup.config, "new.example.com").is_some(),
+                finished,
+                "shutdown_timeout = {shutdown_timeout}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_swaps_config() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.toml");
+        std::fs::write(&path, "records = []\n").unwrap();
+        let mut setup = setup(
+            single_record_config(),
+            ScriptedDns::new(Vec::new()),
+            path.to_str().unwrap(),
+        );
+        let mut state = State::default();
+        let (sender, mut signals) = Signals::channel();
+
+        let signal = |signal| {
+            sender.send(signal).unwrap();
+            tokio::time::sleep(Duration::from_millis(100))
+        };
+        let drive = async {
+            // An invalid file is not loaded...
+            signal(Signal::Reload).await;
+            // ...and a valid one is, and its records checked right away
+            std::fs::write(
+                &path,
+                "[[records]]\nname = \"reloaded.example.com\"\nhosted_zone_id = \"Z1\"\n",
+            )
+            .unwrap();
+            signal(Signal::Reload).await;
+            signal(Signal::Shutdown).await;
+        };
+        let (result, ()) =
+            tokio::join!(run_continuous(&mut setup, &mut state, &mut signals), drive);
+        result.unwrap();
+
+        assert_eq!(setup.config.records.len(), 1);
+        assert_eq!(setup.config.records[0].name, "reloaded.example.com");
+        assert!(stored(&state, &single_record_config(), "new.example.com").is_some());
+        assert!(stored(&state, &setup.config, "reloaded.example.com").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_run_update_uses_record_sources() {
+        let static_source = IpSourceConfig::Static(StaticSourceConfig {
+            addresses: vec!["81.2.69.160".parse().unwrap()],
+        });
+        let config = Config {
+            records: vec![
+                record("dual.example.com", RecordType::Both, None),
+                record("static.example.com", RecordType::A, Some(static_source)),
+            ],
+            ..Config::default()
+        };
+        let ip_source = MockIpSource::new();
+        let dns_updater = MockDnsUpdater::new();
+
+        run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns_updater),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            dns_updater.simulated_ip("dual.example.com", IpVersion::V4),
+            Some(ip_source.v4)
+        );
+        assert_eq!(
+            dns_updater.simulated_ip("dual.example.com", IpVersion::V6),
+            Some(ip_source.v6)
+        );
+        assert_eq!(
+            dns_updater.simulated_ip("static.example.com", IpVersion::V4),
+            Some("81.2.69.160".parse().unwrap())
+        );
+        assert_eq!(
+            dns_updater.simulated_ip("static.example.com", IpVersion::V6),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_sources_are_set_up_once() {
+        let mut config = Config {
+            records: vec![
+                record(
+                    "services.example.com",
+                    RecordType::A,
+                    Some(IpSourceConfig::Services),
+                ),
+                record("new.example.com", RecordType::A, None),
+            ],
+            ..Config::default()
+        };
+        let mut headers = BTreeMap::new();
+        headers.insert("not a header".to_string(), "x".to_string());
+        config.ip_detection.services = vec![IpServiceConfig::Http(HttpServiceConfig {
+            url: "https://ip.example.com".to_string(),
+            timeout: 5,
+            headers,
+            format: ResponseFormat::Text,
+            allow: Vec::new(),
+        })];
+        let ip_source = MockIpSource::new();
+
+        // A source that cannot be set up fails its records, not the cycle
+        let dns = ScriptedDns::new(Vec::new());
+        let result = run_update(
+            &sources(&config),
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert!(result.records[0]
+            .failed()
+            .unwrap()
+            .to_string()
+            .contains("Could not set up"));
+        assert!(matches!(result.records[1].outcome, Outcome::Created));
+        assert_eq!(
+            *dns.updates.lock().unwrap(),
+            [("new.example.com".to_string(), ip_source.v4)]
+        );
+
+        // Dry runs simulate every source, so nothing is detected for real
+        config.records[0].ip_source = Some(IpSourceConfig::Command(CommandSourceConfig {
+            command: "/nonexistent/get-ip".to_string(),
+            args: Vec::new(),
+            timeout: 5,
+        }));
+        let dry_run = IpSources::new(Box::new(MockIpSource::new()), &config, true);
+        let dns = ScriptedDns::new(Vec::new());
+        let result = run_update(
+            &dry_run,
+            &DnsAccounts::single(&dns),
+            &config,
+            &mut State::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.failures(), 0);
+        assert_eq!(result.records[0].ip, Some(ip_source.v4));
     }
 }
@@ -0,0 +1,228 @@
+//! Noticing when the host's addresses or default routes change, so that records are
+//! checked right away instead of at their next interval. Uses rtnetlink on Linux;
+//! elsewhere the interval is all there is.
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::NetworkChangesConfig;
+
+/// Descriptions of relevant network changes, such as `address 192.0.2.7 added`.
+pub struct NetworkChanges {
+    events: mpsc::UnboundedReceiver<String>,
+    debounce: Duration,
+    /// The first change of a burst and how many followed it, kept here rather than in
+    /// `next` so that a cancelled call does not lose them
+    pending: Option<(String, usize)>,
+}
+
+impl NetworkChanges {
+    /// Starts listening for changes. If that is disabled, unsupported or fails,
+    /// `next` simply never returns.
+    pub fn subscribe(config: &NetworkChangesConfig) -> Self {
+        let (sender, events) = mpsc::unbounded_channel();
+        if !config.enabled {
+            debug!("Not watching for network changes");
+        } else {
+            match listen(sender) {
+                Ok(()) => info!("Watching for address and default route changes"),
+                Err(e) => warn!(
+                    "Cannot watch for network changes, relying on the check interval: {:#}",
+                    e
+                ),
+            }
+        }
+        Self {
+            events,
+            debounce: config.debounce.0,
+            pending: None,
+        }
+    }
+
+    /// Waits for a change, then until the network has been quiet for the debounce
+    /// period, and describes the first change. Cancel safe: if the returned future is
+    /// dropped while debouncing, the next call picks up where it left off.
+    pub async fn next(&mut self) -> String {
+        if self.pending.is_none() {
+            let Some(first) = self.events.recv().await else {
+                return std::future::pending().await;
+            };
+            self.pending = Some((first, 0));
+        }
+        let debounce = self.debounce;
+        while let Ok(Some(change)) = tokio::time::timeout(debounce, self.events.recv()).await {
+            debug!("Network change: {}", change);
+            if let Some((_, more)) = &mut self.pending {
+                *more += 1;
+            }
+        }
+        match self.pending.take().expect("a change is pending") {
+            (first, 0) => first,
+            (first, more) => format!("{first} and {more} more"),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn listen(sender: mpsc::UnboundedSender<String>) -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use rtnetlink::packet_core::NetlinkPayload;
+    use rtnetlink::MulticastGroup;
+
+    let (connection, handle, mut messages) = rtnetlink::new_multicast_connection(&[
+        MulticastGroup::Ipv4Ifaddr,
+        MulticastGroup::Ipv6Ifaddr,
+        MulticastGroup::Ipv4Route,
+        MulticastGroup::Ipv6Route,
+    ])?;
+    tokio::spawn(connection);
+    tokio::spawn(async move {
+        // The connection stops once its last handle is gone
+        let _handle = handle;
+        while let Some((message, _)) = messages.next().await {
+            let NetlinkPayload::InnerMessage(message) = message.payload else {
+                continue;
+            };
+            if let Some(change) = describe(&message) {
+                if sender.send(change).is_err() {
+                    return;
+                }
+            }
+        }
+        warn!("Stopped receiving network change notifications");
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen(_sender: mpsc::UnboundedSender<String>) -> anyhow::Result<()> {
+    anyhow::bail!("only supported on Linux")
+}
+
+/// Describes changes that may move the public address: global addresses coming and
+/// going, and default routes. Link-local addresses and other routes are ignored.
+#[cfg(target_os = "linux")]
+fn describe(message: &rtnetlink::packet_route::RouteNetlinkMessage) -> Option<String> {
+    use rtnetlink::packet_route::address::{AddressAttribute, AddressMessage, AddressScope};
+    use rtnetlink::packet_route::route::{RouteMessage, RouteType};
+    use rtnetlink::packet_route::{AddressFamily, RouteNetlinkMessage};
+
+    let address = |message: &AddressMessage, change: &str| {
+        if message.header.scope != AddressScope::Universe {
+            return None;
+        }
+        // For point-to-point links, `Local` is ours and `Address` the other end
+        let ip = message
+            .attributes
+            .iter()
+            .find_map(|attribute| match attribute {
+                AddressAttribute::Local(ip) => Some(ip),
+                _ => None,
+            })
+            .or_else(|| {
+                message
+                    .attributes
+                    .iter()
+                    .find_map(|attribute| match attribute {
+                        AddressAttribute::Address(ip) => Some(ip),
+                        _ => None,
+                    })
+            })?;
+        Some(format!("address {ip} {change}"))
+    };
+    let route = |message: &RouteMessage, change: &str| {
+        if message.header.destination_prefix_length != 0
+            || message.header.kind != RouteType::Unicast
+        {
+            return None;
+        }
+        let family = match message.header.address_family {
+            AddressFamily::Inet6 => "IPv6",
+            _ => "IPv4",
+        };
+        Some(format!("{family} default route {change}"))
+    };
+
+    match message {
+        RouteNetlinkMessage::NewAddress(message) => address(message, "added"),
+        RouteNetlinkMessage::DelAddress(message) => address(message, "removed"),
+        RouteNetlinkMessage::NewRoute(message) => route(message, "changed"),
+        RouteNetlinkMessage::DelRoute(message) => route(message, "removed"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_next_debounces() {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut changes = NetworkChanges {
+            events,
+            debounce: Duration::from_millis(50),
+            pending: None,
+        };
+
+        sender
+            .send("address 192.0.2.7 removed".to_string())
+            .unwrap();
+        sender.send("address 192.0.2.8 added".to_string()).unwrap();
+        sender
+            .send("IPv4 default route changed".to_string())
+            .unwrap();
+        assert_eq!(changes.next().await, "address 192.0.2.7 removed and 2 more");
+
+        sender.send("address 192.0.2.9 added".to_string()).unwrap();
+        assert_eq!(changes.next().await, "address 192.0.2.9 added");
+
+        // A call dropped while debouncing, as when another branch of a select wins,
+        // leaves the change for the next one
+        sender.send("address 192.0.2.10 added".to_string()).unwrap();
+        let waited = tokio::time::timeout(Duration::from_millis(20), changes.next()).await;
+        assert!(waited.is_err());
+        sender
+            .send("IPv6 default route removed".to_string())
+            .unwrap();
+        assert_eq!(changes.next().await, "address 192.0.2.10 added and 1 more");
+
+        // Without a listener there is never a change
+        drop(sender);
+        let waited = tokio::time::timeout(Duration::from_millis(50), changes.next()).await;
+        assert!(waited.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_describe() {
+        use rtnetlink::packet_route::address::{AddressAttribute, AddressMessage, AddressScope};
+        use rtnetlink::packet_route::route::{RouteMessage, RouteType};
+        use rtnetlink::packet_route::{AddressFamily, RouteNetlinkMessage};
+
+        let mut address = AddressMessage::default();
+        address
+            .attributes
+            .push(AddressAttribute::Address("192.0.2.1".parse().unwrap()));
+        address
+            .attributes
+            .push(AddressAttribute::Local("192.0.2.7".parse().unwrap()));
+        assert_eq!(
+            describe(&RouteNetlinkMessage::NewAddress(address.clone())).as_deref(),
+            Some("address 192.0.2.7 added")
+        );
+        address.header.scope = AddressScope::Link;
+        assert_eq!(describe(&RouteNetlinkMessage::DelAddress(address)), None);
+
+        let mut route = RouteMessage::default();
+        route.header.address_family = AddressFamily::Inet6;
+        route.header.kind = RouteType::Unicast;
+        assert_eq!(
+            describe(&RouteNetlinkMessage::DelRoute(route.clone())).as_deref(),
+            Some("IPv6 default route removed")
+        );
+        route.header.destination_prefix_length = 64;
+        assert_eq!(describe(&RouteNetlinkMessage::NewRoute(route)), None);
+    }
+}
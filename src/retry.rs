@@ -0,0 +1,121 @@
+//! Retrying calls that fail for reasons likely to go away on their own, such as
+//! throttling, timeouts and server errors, with exponential backoff.
+
+use rand::Rng;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::RetryConfig;
+
+/// Delay before retry number `retry` (counting from 0): the base delay doubled for
+/// every earlier retry, capped at the maximum, and with jitter a random share of that.
+pub fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    let delay = config
+        .base_delay_ms
+        .saturating_mul(1u64.checked_shl(retry).unwrap_or(u64::MAX))
+        .min(config.max_delay_ms);
+    let delay = if config.jitter {
+        rand::rng().random_range(0..=delay)
+    } else {
+        delay
+    };
+    Duration::from_millis(delay)
+}
+
+/// Runs `call` until it succeeds, fails with an error `is_retryable` rejects, or
+/// `max_attempts` attempts are used up, sleeping between attempts.
+pub async fn with_retries<T, E, F, Fut>(
+    config: &RetryConfig,
+    what: &str,
+    is_retryable: impl Fn(&E) -> bool,
+    mut call: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Err(e) if is_retryable(&e) && attempt < config.max_attempts => {
+                let delay = backoff(config, attempt - 1);
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                    what, attempt, config.max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn config(max_attempts: u32, jitter: bool) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay_ms: 100,
+            max_delay_ms: 350,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let exact = config(5, false);
+        let delays: Vec<_> = (0..4).map(|retry| backoff(&exact, retry)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(Duration::from_millis).to_vec()
+        );
+        // No overflow however long it has been failing
+        assert_eq!(backoff(&exact, 200), Duration::from_millis(350));
+
+        let jittered = config(5, true);
+        for retry in 0..4 {
+            assert!(backoff(&jittered, retry) <= backoff(&exact, retry));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let config = RetryConfig {
+            base_delay_ms: 1,
+            ..config(3, false)
+        };
+        let calls = AtomicU32::new(0);
+        let flaky = || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("throttled"),
+                _ => Ok(42),
+            }
+        };
+        let result = with_retries(&config, "Flaky call", |_| true, flaky).await;
+        assert_eq!(result, Ok(42));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Errors that retrying cannot fix are returned right away
+        calls.store(0, Ordering::SeqCst);
+        let failing = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>("denied")
+        };
+        let result = with_retries(&config, "Failing call", |e| *e != "denied", failing).await;
+        assert_eq!(result, Err("denied"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // ...and retryable ones once the attempts are used up
+        calls.store(0, Ordering::SeqCst);
+        let result = with_retries(&config, "Failing call", |_| true, failing).await;
+        assert_eq!(result, Err("denied"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
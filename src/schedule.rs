@@ -0,0 +1,155 @@
+//! Deciding which records are due for a check in continuous mode.
+
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+/// When each configured record is next due, by its index in `config.records`.
+pub struct Schedule {
+    intervals: Vec<Duration>,
+    next_due: Vec<Instant>,
+    jitter: Duration,
+}
+
+impl Schedule {
+    /// Every record is due right away.
+    pub fn new(config: &Config, now: Instant) -> Self {
+        Self {
+            intervals: config
+                .records
+                .iter()
+                .map(|record| config.interval_for(record))
+                .collect(),
+            next_due: vec![now; config.records.len()],
+            jitter: config.interval_jitter.0,
+        }
+    }
+
+    /// Carries the timers over to a reloaded config: records that did not change keep
+    /// theirs (brought forward if their interval got shorter), and new or changed
+    /// records are due at `now`.
+    pub fn reload(&mut self, old: &Config, new: &Config, now: Instant) {
+        let mut schedule = Self::new(new, now);
+        for (i, record) in new.records.iter().enumerate() {
+            if let Some(j) = old.records.iter().position(|old| old == record) {
+                schedule.next_due[i] = self.next_due[j].min(now + schedule.intervals[i]);
+            }
+        }
+        *self = schedule;
+    }
+
+    /// Indices of the records due at `now`, which are then scheduled one interval later.
+    pub fn take_due(&mut self, now: Instant) -> Vec<usize> {
+        let due: Vec<usize> = (0..self.next_due.len())
+            .filter(|&i| self.next_due[i] <= now)
+            .collect();
+        for &i in &due {
+            self.next_due[i] = now + self.intervals[i];
+        }
+        due
+    }
+
+    /// Makes every record due at `now`, e.g. because the network changed.
+    pub fn set_all_due(&mut self, now: Instant) {
+        self.next_due.fill(now);
+    }
+
+    /// When to wake up for the next due record: its due time plus a random share
+    /// of the jitter. Records that are due by then are checked together.
+    pub fn next_wake(&self) -> Option<Instant> {
+        let next = *self.next_due.iter().min()?;
+        let jitter = if self.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            rand::rng().random_range(Duration::ZERO..=self.jitter)
+        };
+        Some(next + jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AssumeRoleConfig, DnsRecord, HumanDuration, RecordType};
+
+    fn config(intervals: &[Option<u64>]) -> Config {
+        Config {
+            records: intervals
+                .iter()
+                .enumerate()
+                .map(|(i, interval)| DnsRecord {
+                    name: format!("host{i}.example.com"),
+                    hosted_zone_id: None,
+                    ttl: 300,
+                    record_type: RecordType::A,
+                    ip_source: None,
+                    assume_role: AssumeRoleConfig::default(),
+                    interval: interval.map(HumanDuration::from_secs),
+                })
+                .collect(),
+            interval: HumanDuration::from_secs(60),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_take_due() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(&config(&[None, Some(15), Some(600)]), start);
+
+        assert_eq!(schedule.take_due(start), [0, 1, 2]);
+        assert_eq!(schedule.next_wake(), Some(start + Duration::from_secs(15)));
+
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(schedule.take_due(at(15)), [1]);
+        assert_eq!(schedule.take_due(at(30)), [1]);
+        assert_eq!(schedule.take_due(at(45)), [1]);
+        assert!(schedule.take_due(at(59)).is_empty());
+        assert_eq!(schedule.take_due(at(60)), [0, 1]);
+        assert_eq!(schedule.next_wake(), Some(at(75)));
+        // A late wake-up catches up with everything that became due meanwhile
+        assert_eq!(schedule.take_due(at(700)), [0, 1, 2]);
+
+        schedule.set_all_due(at(701));
+        assert_eq!(schedule.next_wake(), Some(at(701)));
+        assert_eq!(schedule.take_due(at(701)), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_reload() {
+        let start = Instant::now();
+        let old = config(&[None, Some(600), Some(600)]);
+        let mut schedule = Schedule::new(&old, start);
+        schedule.take_due(start);
+
+        let mut new = config(&[None, Some(600), Some(600), None]);
+        new.interval = HumanDuration::from_secs(30);
+        new.records[1].ttl = 60;
+        let at = |secs| start + Duration::from_secs(secs);
+        schedule.reload(&old, &new, at(10));
+
+        // host1 changed and host3 is new; host0 keeps its timer, brought forward to
+        // the shorter interval, and host2 keeps its own
+        assert_eq!(schedule.take_due(at(10)), [1, 3]);
+        assert_eq!(schedule.next_wake(), Some(at(40)));
+        assert_eq!(schedule.take_due(at(40)), [0, 3]);
+        assert_eq!(schedule.take_due(at(599)), [0, 3]);
+        assert_eq!(schedule.take_due(at(600)), [2]);
+    }
+
+    #[test]
+    fn test_next_wake_jitter() {
+        let start = Instant::now();
+        let mut config = config(&[None]);
+        config.interval_jitter = HumanDuration::from_secs(10);
+        let mut schedule = Schedule::new(&config, start);
+        schedule.take_due(start);
+
+        for _ in 0..20 {
+            let wake = schedule.next_wake().unwrap();
+            assert!(wake >= start + Duration::from_secs(60));
+            assert!(wake <= start + Duration::from_secs(70));
+        }
+    }
+}
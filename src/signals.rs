@@ -0,0 +1,194 @@
+//! Signals that control continuous mode: SIGTERM and SIGINT shut it down, SIGHUP
+//! reloads the config file and SIGUSR1 checks every record right away. Saving the
+//! config file counts as a SIGHUP.
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// How long the config file has to stay untouched before it is reloaded, as editors
+/// often save in several steps
+const CONFIG_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Shutdown,
+    Reload,
+    CheckNow,
+}
+
+/// Signals as they arrive, in order.
+pub struct Signals {
+    sender: mpsc::UnboundedSender<Signal>,
+    receiver: mpsc::UnboundedReceiver<Signal>,
+    /// Watches the config file for as long as it is kept
+    watcher: Option<RecommendedWatcher>,
+}
+
+impl Signals {
+    /// Takes over the signals from their default handling, which is to exit at once.
+    #[cfg(unix)]
+    pub fn listen() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for (kind, name, action) in [
+            (SignalKind::terminate(), "SIGTERM", Signal::Shutdown),
+            (SignalKind::interrupt(), "SIGINT", Signal::Shutdown),
+            (SignalKind::hangup(), "SIGHUP", Signal::Reload),
+            (SignalKind::user_defined1(), "SIGUSR1", Signal::CheckNow),
+        ] {
+            let mut stream = signal(kind)?;
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while stream.recv().await.is_some() {
+                    info!("Received {}", name);
+                    if sender.send(action).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        Ok(Self {
+            sender,
+            receiver,
+            watcher: None,
+        })
+    }
+
+    /// Only Ctrl-C, which shuts down, is available outside Unix.
+    #[cfg(not(unix))]
+    pub fn listen() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let ctrl_c = sender.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                info!("Received Ctrl-C");
+                if ctrl_c.send(Signal::Shutdown).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            sender,
+            receiver,
+            watcher: None,
+        })
+    }
+
+    /// Signals sent through the returned sender instead of by the OS.
+    #[cfg(test)]
+    pub fn channel() -> (mpsc::UnboundedSender<Signal>, Self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let signals = Self {
+            sender: sender.clone(),
+            receiver,
+            watcher: None,
+        };
+        (sender, signals)
+    }
+
+    /// Also reloads when the config file at `path` is written or replaced. Without
+    /// file notifications, SIGHUP still works.
+    pub fn watch_config(&mut self, path: &Path) {
+        match watch(path, self.sender.clone()) {
+            Ok(watcher) => {
+                info!("Watching {} for changes", path.display());
+                self.watcher = Some(watcher);
+            }
+            Err(e) => warn!(
+                "Cannot watch {} for changes, reload with SIGHUP instead: {:#}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// The next signal.
+    pub async fn recv(&mut self) -> Signal {
+        // Never `None`, as `self.sender` is still around
+        self.receiver
+            .recv()
+            .await
+            .expect("signal channel closed while in use")
+    }
+}
+
+/// Watches the directory rather than the file itself, so that editors and deployment
+/// tools that replace the file instead of writing to it are noticed as well.
+fn watch(path: &Path, sender: mpsc::UnboundedSender<Signal>) -> notify::Result<RecommendedWatcher> {
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let (changes, mut changed) = mpsc::unbounded_channel();
+    let on_event = move |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == file_name.as_deref());
+            if relevant {
+                debug!("Config file event: {:?}", event.kind);
+                let _ = changes.send(());
+            }
+        }
+        Err(e) => warn!("Error watching the config file: {}", e),
+    };
+    let mut watcher = notify::recommended_watcher(on_event)?;
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while changed.recv().await.is_some() {
+            loop {
+                let more = tokio::time::timeout(CONFIG_SETTLE_TIME, changed.recv()).await;
+                if !matches!(more, Ok(Some(()))) {
+                    break;
+                }
+            }
+            info!("Config file changed");
+            if sender.send(Signal::Reload).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_watch_config() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("config.toml");
+        std::fs::write(&path, "interval = 60").unwrap();
+        let (_sender, mut signals) = Signals::channel();
+        signals.watch_config(&path);
+        let wait = Duration::from_secs(5);
+
+        // Other files in the same directory do not count, and a save in several
+        // steps is reloaded once
+        std::fs::write(temp_dir.path().join("state.json"), "{}").unwrap();
+        std::fs::write(&path, "").unwrap();
+        std::fs::write(&path, "interval = 30").unwrap();
+        assert_eq!(timeout(wait, signals.recv()).await, Ok(Signal::Reload));
+        assert!(timeout(CONFIG_SETTLE_TIME * 2, signals.recv())
+            .await
+            .is_err());
+
+        // Replacing the file, as many editors do
+        let replacement = temp_dir.path().join("config.toml.new");
+        std::fs::write(&replacement, "interval = 90").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        assert_eq!(timeout(wait, signals.recv()).await, Ok(Signal::Reload));
+    }
+}
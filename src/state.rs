@@ -0,0 +1,363 @@
+//! What earlier cycles learned about each record, kept in a file so that a record
+//! whose address has not changed does not cost a Route53 call every cycle.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use crate::ip::IpVersion;
+
+/// Why the state file could not be written.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to write state file: {}", path.display())]
+pub struct StateError {
+    path: PathBuf,
+    #[source]
+    source: std::io::Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RecordState {
+    /// The address last detected for the record
+    pub detected: IpAddr,
+    /// The address Route53 last had or was given, if that is known
+    pub published: Option<IpAddr>,
+    /// When Route53 last confirmed `published`
+    pub verified_at: Option<DateTime<Utc>>,
+    /// How the record was configured when `published` was confirmed
+    #[serde(default)]
+    pub settings: Option<RecordSettings>,
+}
+
+/// The parts of a record's configuration, besides its address, that Route53 has to
+/// agree with. A record whose settings changed is checked again, even if its address
+/// did not.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RecordSettings {
+    /// As configured, `None` if it is looked up from the record name
+    pub hosted_zone_id: Option<String>,
+    /// The role the record is changed through, `None` for the default credentials
+    pub role_arn: Option<String>,
+    pub ttl: i64,
+}
+
+/// What is known about each record. The default state is empty and kept in memory only.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct State {
+    /// The file this state is saved to
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// By record name and type, and the zone and role if configured, e.g.
+    /// `home.example.com A in Z1234567890ABC`
+    records: BTreeMap<String, RecordState>,
+}
+
+/// Records with the same name in different zones or accounts are kept apart. Keys
+/// from before zones and roles were part of them still match records with neither;
+/// the others are no longer looked at.
+fn key(name: &str, version: IpVersion, settings: &RecordSettings) -> String {
+    let mut key = format!(
+        "{} {}",
+        name.trim_end_matches('.').to_ascii_lowercase(),
+        version.record_type()
+    );
+    if let Some(zone) = &settings.hosted_zone_id {
+        key.push_str(&format!(" in {zone}"));
+    }
+    if let Some(role_arn) = &settings.role_arn {
+        key.push_str(&format!(" as {role_arn}"));
+    }
+    key
+}
+
+impl State {
+    /// Reads the state file, which [`State::save`] then writes back to. A missing file
+    /// is an empty state, and so is one that cannot be read or parsed: the state only
+    /// saves calls, Route53 has the truth.
+    pub async fn load(path: &Path) -> Self {
+        let records = match tokio::fs::read_to_string(path).await {
+            Ok(content) => match serde_json::from_str::<State>(&content) {
+                Ok(state) => state.records,
+                Err(e) => {
+                    warn!("Ignoring corrupt state file {}: {}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("No state file at {} yet", path.display());
+                BTreeMap::new()
+            }
+            Err(e) => {
+                warn!("Ignoring unreadable state file {}: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+        Self {
+            path: Some(path.to_path_buf()),
+            records,
+        }
+    }
+
+    /// Replaces the state file atomically, if there is one: the new state is written
+    /// and synced to a temporary file next to it, which is then renamed over the old one.
+    pub async fn save(&self) -> Result<(), StateError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let error = |source| StateError {
+            path: path.to_path_buf(),
+            source,
+        };
+        let content = serde_json::to_vec_pretty(self).expect("state is serializable");
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut file = tokio::fs::File::create(&temp_path).await.map_err(error)?;
+        file.write_all(&content).await.map_err(error)?;
+        file.sync_all().await.map_err(error)?;
+        drop(file);
+        tokio::fs::rename(&temp_path, path).await.map_err(error)
+    }
+
+    pub fn get(
+        &self,
+        name: &str,
+        version: IpVersion,
+        settings: &RecordSettings,
+    ) -> Option<&RecordState> {
+        self.records.get(&key(name, version, settings))
+    }
+
+    /// Whether Route53 is known to have `ip` for the record with these `settings`,
+    /// confirmed less than `reconcile_interval` before `now`.
+    pub fn is_published(
+        &self,
+        name: &str,
+        version: IpVersion,
+        ip: IpAddr,
+        settings: &RecordSettings,
+        reconcile_interval: Duration,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let Some(record) = self.get(name, version, settings) else {
+            return false;
+        };
+        let fresh = record.verified_at.is_some_and(|verified_at| {
+            chrono::Duration::from_std(reconcile_interval)
+                .is_ok_and(|interval| now < verified_at + interval)
+        });
+        record.detected == ip
+            && record.published == Some(ip)
+            && record.settings.as_ref() == Some(settings)
+            && fresh
+    }
+
+    /// Remembers the address detected for a record.
+    pub fn detected(
+        &mut self,
+        name: &str,
+        version: IpVersion,
+        ip: IpAddr,
+        settings: &RecordSettings,
+    ) {
+        self.records
+            .entry(key(name, version, settings))
+            .and_modify(|record| record.detected = ip)
+            .or_insert(RecordState {
+                detected: ip,
+                published: None,
+                verified_at: None,
+                settings: None,
+            });
+    }
+
+    /// Remembers that Route53 had or was given `ip` for a record with these
+    /// `settings` at `now`.
+    pub fn published(
+        &mut self,
+        name: &str,
+        version: IpVersion,
+        ip: IpAddr,
+        settings: RecordSettings,
+        now: DateTime<Utc>,
+    ) {
+        self.records.insert(
+            key(name, version, &settings),
+            RecordState {
+                detected: ip,
+                published: Some(ip),
+                verified_at: Some(now),
+                settings: Some(settings),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn settings() -> RecordSettings {
+        RecordSettings {
+            hosted_zone_id: Some("Z1234567890ABC".to_string()),
+            role_arn: None,
+            ttl: 300,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        let mut state = State::load(&path).await;
+        assert!(state.records.is_empty());
+
+        let now = Utc::now();
+        let ip: IpAddr = "81.2.69.160".parse().unwrap();
+        state.published("Home.example.com.", IpVersion::V4, ip, settings(), now);
+        state.detected(
+            "home.example.com",
+            IpVersion::V6,
+            "2001:db8::1".parse().unwrap(),
+            &settings(),
+        );
+        state.save().await.unwrap();
+
+        let loaded = State::load(&path).await;
+        assert_eq!(loaded, state);
+        assert_eq!(
+            loaded
+                .get("home.example.com", IpVersion::V4, &settings())
+                .unwrap()
+                .published,
+            Some(ip)
+        );
+        // Nothing is left behind next to it
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        std::fs::write(&path, "{ not json").unwrap();
+        assert!(State::load(&path).await.records.is_empty());
+
+        // Without a file, nothing is saved
+        State::default().save().await.unwrap();
+    }
+
+    #[test]
+    fn test_is_published() {
+        let now = Utc::now();
+        let day = Duration::from_secs(86_400);
+        let ip: IpAddr = "81.2.69.160".parse().unwrap();
+        let other: IpAddr = "81.2.69.161".parse().unwrap();
+        let set = settings();
+
+        let mut state = State::default();
+        state.published("home.example.com", IpVersion::V4, ip, set.clone(), now);
+        assert!(state.is_published("home.example.com", IpVersion::V4, ip, &set, day, now));
+        assert!(!state.is_published("home.example.com", IpVersion::V4, other, &set, day, now));
+        assert!(!state.is_published("home.example.com", IpVersion::V6, ip, &set, day, now));
+        assert!(!state.is_published("other.example.com", IpVersion::V4, ip, &set, day, now));
+
+        // Due for a full reconcile
+        let later = now + chrono::Duration::from_std(day).unwrap();
+        assert!(!state.is_published("home.example.com", IpVersion::V4, ip, &set, day, later));
+
+        // Configured differently since: with a new TTL, in another zone or account
+        for changed in [
+            RecordSettings {
+                ttl: 60,
+                ..settings()
+            },
+            RecordSettings {
+                hosted_zone_id: None,
+                ..settings()
+            },
+            RecordSettings {
+                role_arn: Some("arn:aws:iam::111111111111:role/dns".to_string()),
+                ..settings()
+            },
+        ] {
+            assert!(!state.is_published("home.example.com", IpVersion::V4, ip, &changed, day, now));
+        }
+
+        // A new address that could not be published yet
+        state.detected("home.example.com", IpVersion::V4, other, &set);
+        assert!(!state.is_published("home.example.com", IpVersion::V4, other, &set, day, now));
+        assert!(!state.is_published("home.example.com", IpVersion::V4, ip, &set, day, now));
+    }
+
+    #[test]
+    fn test_same_name_in_other_zone() {
+        let now = Utc::now();
+        let day = Duration::from_secs(86_400);
+        let ip: IpAddr = "81.2.69.160".parse().unwrap();
+        let other: IpAddr = "81.2.69.161".parse().unwrap();
+        let zone = settings();
+        let other_zone = RecordSettings {
+            hosted_zone_id: Some("Z0987654321XYZ".to_string()),
+            role_arn: Some("arn:aws:iam::111111111111:role/dns".to_string()),
+            ..settings()
+        };
+
+        let mut state = State::default();
+        state.published("home.example.com", IpVersion::V4, ip, zone.clone(), now);
+        state.published(
+            "home.example.com",
+            IpVersion::V4,
+            other,
+            other_zone.clone(),
+            now,
+        );
+        assert!(state.is_published("home.example.com", IpVersion::V4, ip, &zone, day, now));
+        assert!(state.is_published(
+            "home.example.com",
+            IpVersion::V4,
+            other,
+            &other_zone,
+            day,
+            now
+        ));
+
+        // A failure in one zone leaves the other alone
+        state.detected("home.example.com", IpVersion::V4, other, &zone);
+        assert!(!state.is_published("home.example.com", IpVersion::V4, other, &zone, day, now));
+        assert!(state.is_published(
+            "home.example.com",
+            IpVersion::V4,
+            other,
+            &other_zone,
+            day,
+            now
+        ));
+    }
+
+    #[test]
+    fn test_keys_without_zone_or_role() {
+        // State files from before zones and roles were part of the key
+        let state: State = serde_json::from_str(
+            r#"{"records": {"home.example.com A": {
+                "detected": "81.2.69.160",
+                "published": "81.2.69.160",
+                "verified_at": "2026-01-01T00:00:00Z"
+            }}}"#,
+        )
+        .unwrap();
+        let looked_up = RecordSettings {
+            hosted_zone_id: None,
+            ..settings()
+        };
+        assert!(state
+            .get("home.example.com", IpVersion::V4, &looked_up)
+            .is_some());
+        assert!(state
+            .get("home.example.com", IpVersion::V4, &settings())
+            .is_none());
+    }
+}
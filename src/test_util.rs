@@ -0,0 +1,234 @@
+//! Local stand-ins for the network services auto-dns talks to, so tests run offline.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// A tiny HTTP server that answers requests with canned responses and
+/// remembers the requests (head and body) it received.
+pub struct HttpStub {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+type Route = (String, u16, String);
+
+impl HttpStub {
+    pub async fn start(status: u16, body: &str) -> Self {
+        Self::start_with_delay(status, body, Duration::ZERO).await
+    }
+
+    /// Like [`HttpStub::start`], but waits `delay` before answering.
+    pub async fn start_with_delay(status: u16, body: &str, delay: Duration) -> Self {
+        Self::spawn(Vec::new(), (status, body.to_string()), delay).await
+    }
+
+    /// Answers requests for each `(path, status, body)` route, and anything else with a 404.
+    pub async fn start_routes(routes: &[(&str, u16, &str)]) -> Self {
+        let routes = routes
+            .iter()
+            .map(|(path, status, body)| (path.to_string(), *status, body.to_string()))
+            .collect();
+        Self::spawn(routes, (404, "not found".to_string()), Duration::ZERO).await
+    }
+
+    async fn spawn(routes: Vec<Route>, fallback: (u16, String), delay: Duration) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let routes = Arc::new(routes);
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let fallback = fallback.clone();
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let (status, body) = routes
+                        .iter()
+                        .find(|(route, _, _)| route == path)
+                        .map(|(_, status, body)| (*status, body.clone()))
+                        .unwrap_or(fallback);
+                    seen.lock().unwrap().push(request);
+
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 {status} Stub\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Reads one request, including a body announced with `Content-Length`.
+async fn read_request(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_len = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_len]).to_lowercase();
+    let body_len: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|len| len.trim().parse().ok())
+        .unwrap_or(0);
+    while request.len() < head_len + body_len {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    Some(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// A UDP server that answers each datagram with whatever `respond` returns for it,
+/// and remembers the datagrams it received.
+pub struct UdpStub {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl UdpStub {
+    pub async fn start(respond: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let request = &buf[..len];
+                seen.lock().unwrap().push(request.to_vec());
+                if let Some(response) = respond(request) {
+                    let _ = socket.send_to(&response, peer).await;
+                }
+            }
+        });
+
+        Self { addr, requests }
+    }
+
+    /// Answers STUN binding requests, always reporting `mapped` as the client's address.
+    pub async fn stun(mapped: IpAddr) -> Self {
+        Self::start(move |request| {
+            let transaction_id: [u8; 12] = request.get(8..20)?.try_into().unwrap();
+            Some(stun_response(&transaction_id, mapped, true))
+        })
+        .await
+    }
+
+    /// A DNS server that answers every query with the same records of type `rtype`.
+    pub async fn dns(rtype: u16, rdata: Vec<Vec<u8>>) -> Self {
+        Self::start(move |query| {
+            let rdata: Vec<&[u8]> = rdata.iter().map(Vec::as_slice).collect();
+            Some(dns_response(query, rtype, &rdata, 0))
+        })
+        .await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Encodes a STUN binding success response carrying `mapped` as a
+/// XOR-MAPPED-ADDRESS (`xor`) or legacy MAPPED-ADDRESS attribute.
+pub fn stun_response(transaction_id: &[u8; 12], mapped: IpAddr, xor: bool) -> Vec<u8> {
+    const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xA4, 0x42];
+
+    let mut key = [0u8; 16];
+    if xor {
+        key[..4].copy_from_slice(&MAGIC_COOKIE);
+        key[4..].copy_from_slice(transaction_id);
+    }
+
+    let (family, address): (u8, Vec<u8>) = match mapped {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    let port = 40000u16 ^ if xor { 0x2112 } else { 0 };
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(address.iter().zip(key).map(|(byte, k)| byte ^ k));
+
+    let attribute_type: u16 = if xor { 0x0020 } else { 0x0001 };
+    let mut attributes = Vec::new();
+    // An unrelated SOFTWARE attribute first, to exercise attribute skipping and padding
+    attributes.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'a', b'd', b'n', 0x00]);
+    attributes.extend_from_slice(&attribute_type.to_be_bytes());
+    attributes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    attributes.extend_from_slice(&value);
+
+    let mut response = vec![0x01, 0x01];
+    response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE);
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(&attributes);
+    response
+}
+
+/// The name asked about in a DNS query.
+pub fn question_name(query: &[u8]) -> String {
+    let mut labels = Vec::new();
+    let mut at = 12;
+    while query[at] != 0 {
+        let len = query[at] as usize;
+        labels.push(String::from_utf8_lossy(&query[at + 1..at + 1 + len]).into_owned());
+        at += 1 + len;
+    }
+    labels.join(".")
+}
+
+/// Builds the response to `query` with one answer of type `rtype` per `rdata` entry,
+/// each pointing back at the question name.
+pub fn dns_response(query: &[u8], rtype: u16, rdata: &[&[u8]], rcode: u8) -> Vec<u8> {
+    let mut response = query.to_vec();
+    response[2] = 0x81; // response, recursion desired
+    response[3] = 0x80 | rcode; // recursion available
+    response[6..8].copy_from_slice(&(rdata.len() as u16).to_be_bytes());
+
+    for data in rdata {
+        response.extend_from_slice(&[0xc0, 0x0c]); // pointer to the question name
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&[0, 1, 0, 0, 0, 60]); // class IN, TTL 60
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(data);
+    }
+    response
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use std::io::Write;
 use std::process::{Command, Stdio};
 use tempfile::TempDir;
@@ -10,7 +12,7 @@ async fn test_write_config_flag() {
     let config_path = temp_dir.path().join("test_config.toml");
 
     // Prepare input for the interactive config creation
-    let input = "us-west-2\ntest_access_key\ntest_secret\n2\nZ123456789\nhome.example.com\n\nZ987654321\noffice.example.com\n600\n";
+    let input = "us-west-2\ntest_access_key\ntest_secret\n2\nZ123456789\nhome.example.com\n\n\nZ987654321\noffice.example.com\n600\nboth\n";
 
     // Run the binary with --write-config flag
     let mut child = Command::new("cargo")
@@ -125,6 +127,14 @@ async fn test_write_config_flag() {
         300
     );
 
+    assert_eq!(
+        first_record
+            .get("record_type")
+            .and_then(|v| v.as_str())
+            .expect("Record type should be a string"),
+        "A"
+    );
+
     // Verify second record
     let second_record = records[1].as_table().expect("Record should be a table");
     assert_eq!(
@@ -150,6 +160,14 @@ async fn test_write_config_flag() {
             .expect("TTL should be an integer"),
         600
     );
+
+    assert_eq!(
+        second_record
+            .get("record_type")
+            .and_then(|v| v.as_str())
+            .expect("Record type should be a string"),
+        "BOTH"
+    );
 }
 
 #[tokio::test]
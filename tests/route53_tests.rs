@@ -0,0 +1,673 @@
+//! Runs auto-dns against a local stand-in for the Route53 XML API, so the real
+//! `DnsUpdater` code path is exercised without AWS credentials.
+
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+
+const XMLNS: &str = "https://route53.amazonaws.com/doc/2013-04-01/";
+
+struct Zone {
+    id: &'static str,
+    name: &'static str,
+    private: bool,
+}
+
+/// Zone ID and `(name, type, value)` of each change in a ChangeResourceRecordSets call
+type ChangeBatch = (String, Vec<(String, String, String)>);
+
+#[derive(Default)]
+struct Route53State {
+    zones: Vec<Zone>,
+    /// Values by zone ID, record name (with trailing dot) and type
+    records: BTreeMap<(String, String, String), String>,
+    change_batches: Vec<ChangeBatch>,
+    /// Zone ID of each ListResourceRecordSets call
+    listings: Vec<String>,
+    /// GetChange calls answered so far; the first one of each change reports PENDING
+    get_change_calls: usize,
+    /// Role ARN of each STS AssumeRole call
+    assumed_roles: Vec<String>,
+}
+
+/// A small, stateful Route53: hosted zone lookup, record listing, change batches
+/// and change status, speaking the same REST-XML protocol as the real API.
+struct Route53Stub {
+    addr: SocketAddr,
+    state: Arc<Mutex<Route53State>>,
+}
+
+impl Route53Stub {
+    async fn start(zones: Vec<Zone>, records: &[(&str, &str, &str, &str)]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(Route53State {
+            zones,
+            records: records
+                .iter()
+                .map(|(zone, name, rtype, value)| {
+                    let key = (zone.to_string(), name.to_string(), rtype.to_string());
+                    (key, value.to_string())
+                })
+                .collect(),
+            ..Route53State::default()
+        }));
+
+        let shared = state.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let state = shared.clone();
+                tokio::spawn(async move {
+                    let Some((head, body)) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let (status, xml) = handle(&state, &head, &body);
+                    let response = format!(
+                        "HTTP/1.1 {status} Stub\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{xml}",
+                        xml.len()
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    fn record(&self, zone: &str, name: &str, rtype: &str) -> Option<String> {
+        let key = (zone.to_string(), name.to_string(), rtype.to_string());
+        self.state.lock().unwrap().records.get(&key).cloned()
+    }
+
+    fn change_batches(&self) -> Vec<ChangeBatch> {
+        self.state.lock().unwrap().change_batches.clone()
+    }
+
+    fn listings(&self) -> Vec<String> {
+        self.state.lock().unwrap().listings.clone()
+    }
+
+    fn assumed_roles(&self) -> Vec<String> {
+        self.state.lock().unwrap().assumed_roles.clone()
+    }
+
+    /// Writes a config for `records` that sends Route53 requests to this stand-in
+    /// and publishes fixed addresses, so no other network access is needed.
+    fn write_config(&self, dir: &TempDir, records: &str) -> String {
+        let content = format!(
+            r#"{records}
+[ip_source]
+type = "static"
+addresses = ["81.2.69.160", "2a00:1450:4001:82b::200e"]
+
+[aws]
+region = "us-east-1"
+access_key_id = "AKIDSTUB"
+secret_access_key = "stub-secret"
+endpoint_url = "http://{}"
+"#,
+            self.addr
+        );
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_len = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+    let body_len: usize = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or(0);
+    while request.len() < head_len + body_len {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let body = String::from_utf8_lossy(&request[head_len..]).into_owned();
+    Some((head, body))
+}
+
+fn handle(state: &Mutex<Route53State>, head: &str, body: &str) -> (u16, String) {
+    let mut request_line = head.split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query: BTreeMap<String, String> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), percent_decode(value)))
+        .collect();
+    let segments: Vec<&str> = path.trim_start_matches("/2013-04-01/").split('/').collect();
+
+    let mut state = state.lock().unwrap();
+    match (method, segments.as_slice()) {
+        ("GET", ["hostedzonesbyname"]) => list_hosted_zones(&state, &query),
+        (_, ["hostedzone", "ZDENIED", "rrset"]) => error(
+            403,
+            "AccessDenied",
+            "User is not authorized to access this resource",
+        ),
+        ("GET", ["hostedzone", "ZBUSY", "rrset"]) => {
+            state.listings.push("ZBUSY".to_string());
+            error(503, "ServiceUnavailable", "Service is unavailable")
+        }
+        ("GET", ["hostedzone", zone, "rrset"]) => {
+            state.listings.push(zone.to_string());
+            list_record_sets(&state, zone, &query)
+        }
+        ("POST", ["hostedzone", zone, "rrset"]) => change_record_sets(&mut state, zone, body),
+        ("GET", ["hostedzone", zone]) => get_hosted_zone(&state, zone),
+        ("GET", ["change", id]) => {
+            state.get_change_calls += 1;
+            let status = if state.get_change_calls == 1 {
+                "PENDING"
+            } else {
+                "INSYNC"
+            };
+            (200, change_info("GetChangeResponse", id, status))
+        }
+        // STS, for AWS_ENDPOINT_URL_STS pointing here
+        ("POST", ["", ""]) if body.contains("Action=AssumeRole") => assume_role(&mut state, body),
+        _ => error(404, "NotFound", &format!("No route for {method} {path}")),
+    }
+}
+
+/// Sort key for names in Route53's listing order: labels compared from the right.
+fn listing_key(name: &str) -> String {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    name.split('.').rev().collect::<Vec<_>>().join(".")
+}
+
+fn list_hosted_zones(state: &Route53State, query: &BTreeMap<String, String>) -> (u16, String) {
+    let start = listing_key(query.get("dnsname").map_or("", String::as_str));
+    let mut zones: Vec<&Zone> = state
+        .zones
+        .iter()
+        .filter(|zone| listing_key(zone.name) >= start)
+        .collect();
+    zones.sort_by_key(|zone| listing_key(zone.name));
+
+    let zones: String = zones
+        .iter()
+        .map(|zone| {
+            format!(
+                "<HostedZone><Id>/hostedzone/{}</Id><Name>{}</Name><CallerReference>{}</CallerReference>\
+                 <Config><PrivateZone>{}</PrivateZone></Config></HostedZone>",
+                zone.id, zone.name, zone.id, zone.private
+            )
+        })
+        .collect();
+    let xml = format!(
+        "<?xml version=\"1.0\"?><ListHostedZonesByNameResponse xmlns=\"{XMLNS}\">\
+         <HostedZones>{zones}</HostedZones><IsTruncated>false</IsTruncated><MaxItems>10</MaxItems>\
+         </ListHostedZonesByNameResponse>"
+    );
+    (200, xml)
+}
+
+fn get_hosted_zone(state: &Route53State, zone: &str) -> (u16, String) {
+    let Some(found) = state.zones.iter().find(|z| z.id == zone) else {
+        return error(
+            404,
+            "NoSuchHostedZone",
+            &format!("No hosted zone found with ID: {zone}"),
+        );
+    };
+    let record_sets = state
+        .records
+        .keys()
+        .filter(|(record_zone, _, _)| record_zone == zone)
+        .count();
+    let xml = format!(
+        "<?xml version=\"1.0\"?><GetHostedZoneResponse xmlns=\"{XMLNS}\">\
+         <HostedZone><Id>/hostedzone/{}</Id><Name>{}</Name><CallerReference>{}</CallerReference>\
+         <Config><PrivateZone>{}</PrivateZone></Config>\
+         <ResourceRecordSetCount>{record_sets}</ResourceRecordSetCount></HostedZone>\
+         </GetHostedZoneResponse>",
+        found.id, found.name, found.id, found.private
+    );
+    (200, xml)
+}
+
+fn list_record_sets(
+    state: &Route53State,
+    zone: &str,
+    query: &BTreeMap<String, String>,
+) -> (u16, String) {
+    if !state.zones.iter().any(|z| z.id == zone) {
+        return error(
+            404,
+            "NoSuchHostedZone",
+            &format!("No hosted zone found with ID: {zone}"),
+        );
+    }
+    let start = (
+        listing_key(query.get("name").map_or("", String::as_str)),
+        query.get("type").cloned().unwrap_or_default(),
+    );
+    let mut sets: Vec<(String, &String, &String, &String)> = state
+        .records
+        .iter()
+        .filter(|((record_zone, _, _), _)| record_zone == zone)
+        .map(|((_, name, rtype), value)| (listing_key(name), name, rtype, value))
+        .filter(|(key, _, rtype, _)| (key.clone(), rtype.to_string()) >= start)
+        .collect();
+    sets.sort();
+
+    let max_items: usize = query.get("maxitems").map_or(100, |n| n.parse().unwrap());
+    let next = match sets.get(max_items) {
+        Some((_, name, rtype, _)) => format!(
+            "<IsTruncated>true</IsTruncated><NextRecordName>{name}</NextRecordName>\
+             <NextRecordType>{rtype}</NextRecordType>"
+        ),
+        None => "<IsTruncated>false</IsTruncated>".to_string(),
+    };
+    let sets: String = sets
+        .iter()
+        .take(max_items)
+        .map(|(_, name, rtype, value)| {
+            format!(
+                "<ResourceRecordSet><Name>{name}</Name><Type>{rtype}</Type><TTL>300</TTL>\
+                 <ResourceRecords><ResourceRecord><Value>{value}</Value></ResourceRecord></ResourceRecords>\
+                 </ResourceRecordSet>"
+            )
+        })
+        .collect();
+    let xml = format!(
+        "<?xml version=\"1.0\"?><ListResourceRecordSetsResponse xmlns=\"{XMLNS}\">\
+         <ResourceRecordSets>{sets}</ResourceRecordSets>{next}<MaxItems>{max_items}</MaxItems>\
+         </ListResourceRecordSetsResponse>"
+    );
+    (200, xml)
+}
+
+fn change_record_sets(state: &mut Route53State, zone: &str, body: &str) -> (u16, String) {
+    let tag = |xml: &str, name: &str| {
+        Regex::new(&format!("<{name}>(.*?)</{name}>"))
+            .unwrap()
+            .captures(xml)
+            .map(|c| c[1].to_string())
+            .unwrap_or_default()
+    };
+    let changes: Vec<(String, String, String)> = Regex::new("(?s)<Change>(.*?)</Change>")
+        .unwrap()
+        .captures_iter(body)
+        .map(|c| (tag(&c[1], "Name"), tag(&c[1], "Type"), tag(&c[1], "Value")))
+        .collect();
+    if changes.is_empty() {
+        return error(400, "InvalidChangeBatch", "No changes in batch");
+    }
+
+    for (name, rtype, value) in &changes {
+        let key = (zone.to_string(), name.clone(), rtype.clone());
+        state.records.insert(key, value.clone());
+    }
+    state.change_batches.push((zone.to_string(), changes));
+    let id = format!("C{}", state.change_batches.len());
+    (
+        200,
+        change_info("ChangeResourceRecordSetsResponse", &id, "PENDING"),
+    )
+}
+
+fn assume_role(state: &mut Route53State, body: &str) -> (u16, String) {
+    let role_arn = body
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("RoleArn="))
+        .map(percent_decode)
+        .unwrap_or_default();
+    state.assumed_roles.push(role_arn.clone());
+    let xml = format!(
+        "<AssumeRoleResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\"><AssumeRoleResult>\
+         <Credentials><AccessKeyId>ASIASTUB</AccessKeyId><SecretAccessKey>stub-secret</SecretAccessKey>\
+         <SessionToken>stub-token</SessionToken><Expiration>2099-01-01T00:00:00Z</Expiration></Credentials>\
+         <AssumedRoleUser><AssumedRoleId>AROASTUB:auto-dns</AssumedRoleId><Arn>{role_arn}</Arn></AssumedRoleUser>\
+         </AssumeRoleResult><ResponseMetadata><RequestId>stub</RequestId></ResponseMetadata></AssumeRoleResponse>"
+    );
+    (200, xml)
+}
+
+fn change_info(response: &str, id: &str, status: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?><{response} xmlns=\"{XMLNS}\"><ChangeInfo><Id>/change/{id}</Id>\
+         <Status>{status}</Status><SubmittedAt>2024-01-01T00:00:00.000Z</SubmittedAt></ChangeInfo></{response}>"
+    )
+}
+
+fn error(status: u16, code: &str, message: &str) -> (u16, String) {
+    let xml = format!(
+        "<?xml version=\"1.0\"?><ErrorResponse xmlns=\"{XMLNS}\"><Error><Type>Sender</Type>\
+         <Code>{code}</Code><Message>{message}</Message></Error><RequestId>stub</RequestId></ErrorResponse>"
+    );
+    (status, xml)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+async fn run_once(config_path: &str, extra_args: &[&str]) -> Output {
+    Command::new("cargo")
+        .args(["run", "--", "--config", config_path, "--once"])
+        .args(extra_args)
+        .output()
+        .await
+        .expect("Failed to run command")
+}
+
+fn example_zone() -> Zone {
+    Zone {
+        id: "Z1EXAMPLE",
+        name: "example.com.",
+        private: false,
+    }
+}
+
+#[tokio::test]
+async fn test_update_cycle_against_route53_stub() {
+    let route53 = Route53Stub::start(
+        vec![example_zone()],
+        &[
+            ("Z1EXAMPLE", "current.example.com.", "A", "81.2.69.160"),
+            ("Z1EXAMPLE", "stale.example.com.", "A", "81.2.69.1"),
+        ],
+    )
+    .await;
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "current.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+
+[[records]]
+name = "stale.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+
+[[records]]
+name = "new.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+record_type = "BOTH"
+"#,
+    );
+
+    let output = run_once(&config_path, &[]).await;
+    assert!(
+        output.status.success(),
+        "Process failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // The zone is listed once for all three records
+    assert_eq!(route53.listings(), ["Z1EXAMPLE"]);
+
+    // The stale record is updated and the missing ones created, all in one batch
+    let batches = route53.change_batches();
+    assert_eq!(batches.len(), 1, "{batches:?}");
+    assert_eq!(batches[0].0, "Z1EXAMPLE");
+    assert_eq!(batches[0].1.len(), 3);
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "stale.example.com.", "A"),
+        Some("81.2.69.160".to_string())
+    );
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "new.example.com.", "A"),
+        Some("81.2.69.160".to_string())
+    );
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "new.example.com.", "AAAA"),
+        Some("2a00:1450:4001:82b::200e".to_string())
+    );
+
+    // A second run finds everything up to date
+    let output = run_once(&config_path, &[]).await;
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("all records up to date"),
+        "Actual stdout: {stdout}"
+    );
+    assert_eq!(route53.change_batches().len(), 1);
+}
+
+#[tokio::test]
+async fn test_zone_lookup_and_wait_for_sync_against_route53_stub() {
+    let route53 = Route53Stub::start(
+        vec![
+            example_zone(),
+            Zone {
+                id: "Z2INTERNAL",
+                name: "internal.example.com.",
+                private: true,
+            },
+        ],
+        &[],
+    )
+    .await;
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "home.lab.example.com"
+"#,
+    );
+
+    let output = run_once(&config_path, &["--wait-for-sync"]).await;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "Process failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "home.lab.example.com.", "A"),
+        Some("81.2.69.160".to_string())
+    );
+    assert!(stdout.contains("in sync after"), "Actual stdout: {stdout}");
+
+    // The closest zone is private, so auto-dns must not guess
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "host.internal.example.com"
+"#,
+    );
+    let output = run_once(&config_path, &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Cannot pick a hosted zone for host.internal.example.com"),
+        "Actual stdout: {stdout}"
+    );
+}
+
+#[tokio::test]
+async fn test_access_denied_against_route53_stub() {
+    let route53 = Route53Stub::start(vec![example_zone()], &[]).await;
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "ZDENIED"
+"#,
+    );
+
+    let output = run_once(&config_path, &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Access denied for Route53 ListResourceRecordSets in zone ZDENIED"),
+        "Actual stdout: {stdout}"
+    );
+    assert!(route53.change_batches().is_empty());
+
+    // Records in other zones are still updated, and the exit code tells a partial failure
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "ZDENIED"
+
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+"#,
+    );
+    let output = run_once(&config_path, &[]).await;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "home.example.com.", "A"),
+        Some("81.2.69.160".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_assume_role_without_region_against_route53_stub() {
+    let route53 = Route53Stub::start(vec![example_zone()], &[]).await;
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+role_arn = "arn:aws:iam::222222222222:role/dns"
+"#,
+    );
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, config.replace("region = \"us-east-1\"\n", "")).unwrap();
+
+    // No region anywhere, so STS has to make do with the same fallback as Route53
+    let output = Command::new("cargo")
+        .args(["run", "--", "--config", &config_path, "--once"])
+        .env_remove("AWS_REGION")
+        .env_remove("AWS_DEFAULT_REGION")
+        .env_remove("AWS_PROFILE")
+        .env("AWS_CONFIG_FILE", temp_dir.path().join("no-aws-config"))
+        .env("AWS_EC2_METADATA_DISABLED", "true")
+        .env("AWS_ENDPOINT_URL_STS", format!("http://{}", route53.addr))
+        .output()
+        .await
+        .expect("Failed to run command");
+    assert!(
+        output.status.success(),
+        "Process failed with stdout: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert_eq!(
+        route53.assumed_roles(),
+        ["arn:aws:iam::222222222222:role/dns"]
+    );
+    assert_eq!(
+        route53.record("Z1EXAMPLE", "home.example.com.", "A"),
+        Some("81.2.69.160".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_retry_policy_against_route53_stub() {
+    let route53 = Route53Stub::start(vec![example_zone()], &[]).await;
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = route53.write_config(
+        &temp_dir,
+        r#"
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "ZBUSY"
+
+[retry]
+max_attempts = 2
+base_delay_ms = 1
+"#,
+    );
+
+    // Only [retry] decides how often a call is attempted, not the AWS SDK as well
+    let output = run_once(&config_path, &[]).await;
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(route53.listings(), ["ZBUSY", "ZBUSY"]);
+}
+
+#[tokio::test]
+async fn test_state_file_against_route53_stub() {
+    let route53 = Route53Stub::start(vec![example_zone()], &[]).await;
+    let temp_dir = TempDir::new().unwrap();
+    let state_path = temp_dir.path().join("state.json");
+    let config_path = route53.write_config(
+        &temp_dir,
+        &format!(
+            r#"
+[[records]]
+name = "home.example.com"
+hosted_zone_id = "Z1EXAMPLE"
+
+[state]
+path = "{}"
+"#,
+            state_path.display()
+        ),
+    );
+
+    let output = run_once(&config_path, &[]).await;
+    assert!(
+        output.status.success(),
+        "Process failed with stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(route53.listings().len(), 1);
+    let state = std::fs::read_to_string(&state_path).unwrap();
+    assert!(state.contains("home.example.com A"), "{state}");
+
+    // The address has not changed, so Route53 is not asked again
+    let output = run_once(&config_path, &[]).await;
+    assert!(output.status.success());
+    assert_eq!(route53.listings().len(), 1);
+    assert_eq!(route53.change_batches().len(), 1);
+}